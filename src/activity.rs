@@ -0,0 +1,278 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::util;
+
+/// Something which happened, as recorded in the [ActivityLog](`ActivityLog`).
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event {
+    /// The index page guessed the target triple of a client from what its browser told it.
+    Guess {
+        project: String,
+        os: String,
+        os_version: String,
+        user_agent: String,
+        /// Not set when no target triple could be guessed.
+        target: Option<String>,
+    },
+    /// A executable, package or patch got downloaded.
+    Download {
+        project: String,
+        target: String,
+        format: String,
+        /// The older revision downloaded or patched from, not set for the latest one.
+        version: Option<String>,
+        /// Whether the download was served without building anything.
+        cache_hit: bool,
+        bytes: u64,
+        user_agent: Option<String>,
+    },
+    /// A target got built.
+    Build {
+        project: String,
+        target: String,
+        /// In seconds, without waiting for a build slot.
+        duration: f64,
+        /// `success` or `failure`.
+        outcome: String,
+        error: Option<String>,
+    },
+}
+
+impl Event {
+    /// The name of the event, as found in the `event` field of the JSON.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Guess { .. } => "guess",
+            Event::Download { .. } => "download",
+            Event::Build { .. } => "build",
+        }
+    }
+
+    pub fn project(&self) -> &str {
+        match self {
+            Event::Guess { project, .. }
+            | Event::Download { project, .. }
+            | Event::Build { project, .. } => project,
+        }
+    }
+
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            Event::Guess { target, .. } => target.as_deref(),
+            Event::Download { target, .. } | Event::Build { target, .. } => Some(target),
+        }
+    }
+}
+
+/// A line of the [ActivityLog](`ActivityLog`).
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Record {
+    /// When it happened, in RFC 3339 format.
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Which [Records](`Record`) to return from [recent](`ActivityLog::recent`),
+/// taken from the query parameters of `/activity`.
+#[derive(Debug, Default, Deserialize)]
+pub struct Filter {
+    pub event: Option<String>,
+    pub project: Option<String>,
+    pub target: Option<String>,
+    /// How many records to return at most, 100 when not set and never more than 1000.
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    fn matches(&self, record: &Record) -> bool {
+        let event = &record.event;
+        self.event.as_deref().is_none_or(|e| e == event.name())
+            && self.project.as_deref().is_none_or(|p| p == event.project())
+            && self
+                .target
+                .as_deref()
+                .is_none_or(|t| Some(t) == event.target())
+    }
+
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(100).min(1000)
+    }
+}
+
+struct Writer {
+    file: File,
+    /// The size of the file, kept track of for knowing when to rotate it.
+    size: u64,
+}
+
+/// Records guesses, downloads and builds as JSONL, one [Record](`Record`) per line.
+///
+/// Once the file grows past its maximum size it gets moved to `<file>.1`, the one
+/// there to `<file>.2` and so on, dropping the oldest one.
+pub struct ActivityLog {
+    path: PathBuf,
+    /// In bytes, 0 to never rotate.
+    max_size: u64,
+    /// How many rotated files to keep.
+    keep: usize,
+    /// Not set when recording is disabled.
+    writer: Option<Mutex<Writer>>,
+}
+
+impl ActivityLog {
+    /// Opens the log at `path` for appending, creating it if it does not exist.
+    pub fn open(path: PathBuf, max_size: u64, keep: usize) -> Result<Self, String> {
+        let writer = Writer {
+            file: open(&path)?,
+            size: fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+        };
+        info!("Recording activity in {path:?}");
+
+        Ok(ActivityLog {
+            path,
+            max_size,
+            keep,
+            writer: Some(Mutex::new(writer)),
+        })
+    }
+
+    /// A log which records nothing.
+    pub fn disabled() -> Self {
+        ActivityLog {
+            path: PathBuf::new(),
+            max_size: 0,
+            keep: 0,
+            writer: None,
+        }
+    }
+
+    /// Appends `event` to the log, problems writing it only get logged.
+    pub async fn record(self: &Arc<Self>, event: Event) {
+        if self.writer.is_none() {
+            return;
+        }
+
+        let record = Record {
+            timestamp: util::rfc3339(SystemTime::now()),
+            event,
+        };
+        // Writing and rotating block, keep them off the runtime.
+        let log = self.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || log.append(record)).await {
+            error!("Failed to record activity in {:?}: {e}", self.path);
+        }
+    }
+
+    fn append(&self, record: Record) {
+        let writer = match &self.writer {
+            Some(w) => w,
+            None => return,
+        };
+
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Failed to serialize {record:?}: {e}");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut writer = writer.lock().unwrap();
+        let full = writer.size > 0 && writer.size + line.len() as u64 > self.max_size;
+        if self.max_size > 0 && full {
+            match self.rotate() {
+                Ok(file) => *writer = Writer { file, size: 0 },
+                Err(e) => error!("Failed to rotate {:?}: {e}", self.path),
+            }
+        }
+
+        match writer.file.write_all(&line) {
+            Ok(()) => writer.size += line.len() as u64,
+            Err(e) => error!("Failed to record activity in {:?}: {e}", self.path),
+        }
+    }
+
+    /// Moves every file one place down the line and opens a new one.
+    fn rotate(&self) -> Result<File, String> {
+        for i in (1..self.keep).rev() {
+            match fs::rename(self.rotated(i), self.rotated(i + 1)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.to_string()),
+                _ => {}
+            }
+        }
+
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated(1)).map_err(|e| e.to_string())?;
+        } else {
+            fs::remove_file(&self.path).map_err(|e| e.to_string())?;
+        }
+
+        open(&self.path)
+    }
+
+    /// The path of the `n`th most recently rotated file.
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        PathBuf::from(path)
+    }
+
+    /// The most recent [Records](`Record`) matching `filter`, newest first.
+    /// Looks through the rotated files too when needed.
+    pub async fn recent(self: &Arc<Self>, filter: Filter) -> Vec<Record> {
+        if self.writer.is_none() {
+            return Vec::new();
+        }
+
+        // The files can be large, read them off the runtime.
+        let log = self.clone();
+        tokio::task::spawn_blocking(move || log.read(&filter))
+            .await
+            .unwrap_or_default()
+    }
+
+    fn read(&self, filter: &Filter) -> Vec<Record> {
+        let limit = filter.limit();
+        let mut records = Vec::new();
+        let files =
+            std::iter::once(self.path.clone()).chain((1..=self.keep).map(|n| self.rotated(n)));
+        for file in files {
+            let contents = match fs::read_to_string(&file) {
+                Ok(c) => c,
+                Err(_) => break,
+            };
+
+            let matching = contents
+                .lines()
+                .rev()
+                .filter_map(|l| serde_json::from_str::<Record>(l).ok())
+                .filter(|r| filter.matches(r));
+            records.extend(matching.take(limit - records.len()));
+            if records.len() >= limit {
+                break;
+            }
+        }
+
+        records
+    }
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {path:?}: {e}"))
+}
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use http::StatusCode;
+use serde::Serialize;
+
+use crate::{
+    cache::Entry,
+    project::{InFlight, Project, Projects},
+};
+
+/// Compares `a` and `b` in constant time, so that how long it takes does not give a token away.
+pub fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The [Project](`Project`) called `name`, or `404 Not Found`.
+pub fn project<'a>(
+    projects: &'a Projects,
+    name: &str,
+) -> Result<&'a Arc<Project>, (StatusCode, String)> {
+    projects
+        .get(name)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("No such project: {name}")))
+}
+
+/// A [Entry](`Entry`) of the cache of `project`.
+#[derive(Debug, Serialize)]
+pub struct CachedEntry {
+    pub project: String,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+/// A key taken out of the cache of `project`.
+#[derive(Debug, Serialize)]
+pub struct Evicted {
+    pub project: String,
+    pub key: String,
+}
+
+/// A [InFlight](`InFlight`) build of `project`.
+#[derive(Debug, Serialize)]
+pub struct Build {
+    pub project: String,
+    #[serde(flatten)]
+    pub build: InFlight,
+}
+
+/// How many times `target_triple` of `project` got downloaded in `format` since starting.
+#[derive(Debug, Serialize)]
+pub struct Downloads {
+    pub project: String,
+    pub target_triple: String,
+    pub format: String,
+    pub count: u64,
+}
@@ -0,0 +1,381 @@
+use std::{
+    collections::BTreeSet,
+    fmt, fs,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header, HeaderMap, Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::{admin, config::Settings};
+
+/// The name requests authenticated with `admin.token` show up under.
+const ADMIN: &str = "admin";
+
+/// What a token may do, every role may do whatever the ones before it may.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Downloading what is built already.
+    Download,
+    /// Downloading targets which are not built yet, building them.
+    Build,
+    /// The admin API.
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Download => "download",
+            Role::Build => "build",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "download" => Ok(Role::Download),
+            "build" => Ok(Role::Build),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!(
+                "Unknown role: {s}, expected \"download\", \"build\" or \"admin\""
+            )),
+        }
+    }
+}
+
+/// A token of the tokens file. Only the sha256 of the secret is kept, see [hash](`hash`).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Token {
+    pub name: String,
+    pub role: Role,
+    pub sha256: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TokensFile {
+    #[serde(default)]
+    tokens: Vec<Token>,
+}
+
+/// Parses a tokens file, a TOML file with a `[[tokens]]` table per token.
+pub fn parse_tokens(contents: &str) -> Result<Vec<Token>, String> {
+    let file: TokensFile = toml::from_str(contents).map_err(|e| e.to_string())?;
+
+    let mut names = BTreeSet::new();
+    let mut tokens = Vec::new();
+    for mut token in file.tokens {
+        if token.name.is_empty() || token.name.contains(':') {
+            return Err(format!("Invalid token name {:?}", token.name));
+        }
+        if !names.insert(token.name.clone()) {
+            return Err(format!("The token {} is listed twice", token.name));
+        }
+        if token.sha256.len() != 64 || !token.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "The sha256 of {} is not 64 hex digits, see `gload token`",
+                token.name
+            ));
+        }
+        token.sha256.make_ascii_lowercase();
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+pub fn read_tokens(path: &Path) -> Result<Vec<Token>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+    parse_tokens(&contents).map_err(|e| format!("Invalid tokens in {path:?}: {e}"))
+}
+
+/// The entry of the tokens file for a token called `name` with the given `secret`.
+pub fn token_entry(name: &str, role: Role, secret: &str) -> Result<String, String> {
+    let file = TokensFile {
+        tokens: vec![Token {
+            name: name.to_string(),
+            role,
+            sha256: hash(secret),
+        }],
+    };
+    toml::to_string(&file).map_err(|e| e.to_string())
+}
+
+/// The sha256 of `secret` as hex, the way it is kept in the tokens file.
+pub fn hash(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// A new random secret, 48 hex digits.
+pub fn new_secret() -> Result<String, String> {
+    let mut bytes = [0u8; 24];
+    getrandom::fill(&mut bytes).map_err(|e| format!("Failed to get random bytes: {e}"))?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// The credentials a request came with.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Credentials {
+    /// `Authorization: Bearer <secret>`
+    Bearer(String),
+    /// `Authorization: Basic <base64 of name:secret>`
+    Basic { name: String, secret: String },
+}
+
+impl Credentials {
+    /// The credentials in `headers`, `Err` when there is a `Authorization` header
+    /// which can not be made sense of.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, String> {
+        let value = match headers.get(header::AUTHORIZATION) {
+            Some(value) => value.to_str().map_err(|e| e.to_string())?,
+            None => return Ok(None),
+        };
+
+        let (scheme, rest) = value
+            .split_once(' ')
+            .ok_or_else(|| "No authorization scheme".to_string())?;
+        let rest = rest.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            Ok(Some(Credentials::Bearer(rest.to_string())))
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = STANDARD.decode(rest).map_err(|e| e.to_string())?;
+            let decoded = String::from_utf8(decoded).map_err(|e| e.to_string())?;
+            let (name, secret) = decoded
+                .split_once(':')
+                .ok_or_else(|| "No ':' between name and secret".to_string())?;
+            Ok(Some(Credentials::Basic {
+                name: name.to_string(),
+                secret: secret.to_string(),
+            }))
+        } else {
+            Err(format!("Unsupported authorization scheme {scheme}"))
+        }
+    }
+
+    /// The `WWW-Authenticate` challenge to answer with when these are refused.
+    fn challenge(this: Option<&Self>) -> &'static str {
+        match this {
+            Some(Credentials::Bearer(_)) => "Bearer",
+            _ => "Basic realm=\"gload\"",
+        }
+    }
+}
+
+/// Who a request came from and what they may do, put in the extensions of every request
+/// by [authenticate](`authenticate`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Access {
+    /// The name of the token used, `None` for anonymous requests.
+    pub name: Option<String>,
+    /// `None` when nothing at all is allowed.
+    pub role: Option<Role>,
+}
+
+impl Access {
+    pub fn allows(&self, role: Role) -> bool {
+        self.role >= Some(role)
+    }
+}
+
+struct State {
+    admin_token: Option<String>,
+    tokens: Vec<Token>,
+    /// Whether there is a tokens file, without one everything but the admin API is open.
+    enabled: bool,
+    public_downloads: bool,
+}
+
+/// Checks credentials against `admin.token` and the tokens file of `auth.tokens_file`.
+pub struct Auth {
+    state: RwLock<State>,
+}
+
+impl Auth {
+    pub fn new(settings: &Settings) -> Result<Self, String> {
+        let auth = Auth {
+            state: RwLock::new(State {
+                admin_token: None,
+                tokens: Vec::new(),
+                enabled: false,
+                public_downloads: true,
+            }),
+        };
+        auth.apply(settings)?;
+        Ok(auth)
+    }
+
+    /// Reads the tokens file again and takes over `admin.token` and `auth.public_downloads`,
+    /// so that tokens can be rotated without restarting. Nothing changes when the tokens
+    /// file is invalid.
+    pub fn apply(&self, settings: &Settings) -> Result<(), String> {
+        let tokens = match &settings.auth.tokens_file {
+            Some(file) => {
+                let tokens = read_tokens(file)?;
+                info!("Read {} tokens from {file:?}", tokens.len());
+                tokens
+            }
+            None => Vec::new(),
+        };
+
+        let mut state = self.state.write().unwrap();
+        *state = State {
+            admin_token: settings.admin.token.clone(),
+            tokens,
+            enabled: settings.auth.tokens_file.is_some(),
+            public_downloads: settings.auth.public_downloads,
+        };
+        Ok(())
+    }
+
+    /// Whether anyone may use the admin API, through `admin.token` or a token with the
+    /// admin role.
+    pub fn has_admin(&self) -> bool {
+        let state = self.state.read().unwrap();
+        state.admin_token.is_some() || state.tokens.iter().any(|t| t.role == Role::Admin)
+    }
+
+    /// What requests without credentials may do.
+    pub fn anonymous(&self) -> Access {
+        let state = self.state.read().unwrap();
+        let role = if !state.enabled {
+            Some(Role::Build)
+        } else if state.public_downloads {
+            Some(Role::Download)
+        } else {
+            None
+        };
+        Access { name: None, role }
+    }
+
+    /// What `credentials` may do, `None` when they are wrong.
+    pub fn identify(&self, credentials: &Credentials) -> Option<Access> {
+        let state = self.state.read().unwrap();
+        let (name, secret) = match credentials {
+            Credentials::Bearer(secret) => {
+                let is_admin = state
+                    .admin_token
+                    .as_ref()
+                    .is_some_and(|t| admin::same(t.as_bytes(), secret.as_bytes()));
+                if is_admin {
+                    return Some(Access {
+                        name: Some(ADMIN.to_string()),
+                        role: Some(Role::Admin),
+                    });
+                }
+                (None, secret)
+            }
+            Credentials::Basic { name, secret } => (Some(name), secret),
+        };
+
+        let hash = hash(secret);
+        state
+            .tokens
+            .iter()
+            .filter(|t| name.map(|n| *n == t.name).unwrap_or(true))
+            .find(|t| admin::same(t.sha256.as_bytes(), hash.as_bytes()))
+            .map(|t| Access {
+                name: Some(t.name.clone()),
+                role: Some(t.role),
+            })
+    }
+}
+
+fn unauthorized(challenge: &str) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, challenge)],
+        "Unauthorized",
+    )
+        .into_response()
+}
+
+/// Works out the [Access](`Access`) of every request from its credentials, with the
+/// [Auth](`Auth`) in a `Extension` layered on top. Wrong credentials are refused right away
+/// rather than treated as anonymous, so that typos dont go unnoticed.
+pub async fn authenticate<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let auth = match request.extensions().get::<Arc<Auth>>() {
+        Some(auth) => auth.clone(),
+        None => return next.run(request).await,
+    };
+
+    let credentials = match Credentials::from_headers(request.headers()) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!(
+                "Refused malformed credentials for {}: {e}",
+                request.uri().path()
+            );
+            return unauthorized(Credentials::challenge(None));
+        }
+    };
+
+    let access = match &credentials {
+        Some(c) => match auth.identify(c) {
+            Some(access) => access,
+            None => {
+                warn!("Refused wrong credentials for {}", request.uri().path());
+                return unauthorized(Credentials::challenge(Some(c)));
+            }
+        },
+        None => auth.anonymous(),
+    };
+    if let Some(name) = &access.name {
+        debug!("Authenticated as {name}");
+    }
+
+    request.extensions_mut().insert(access);
+    next.run(request).await
+}
+
+/// Only lets requests through whose [Access](`Access`) allows `role`, for layering
+/// onto routes as `middleware::from_fn(|r, n| auth::require(Role::Build, r, n))`.
+pub async fn require<B>(role: Role, request: Request<B>, next: Next<B>) -> Response {
+    match request.extensions().get::<Access>() {
+        Some(access) if access.allows(role) => next.run(request).await,
+        access => refuse(access, role, request.uri().path()),
+    }
+}
+
+/// The response to a request to `path` which `access` does not allow, as it needs `role`.
+///
+/// Anonymous requests get `401 Unauthorized` so that they can try again with credentials,
+/// the others `403 Forbidden`.
+pub fn refuse(access: Option<&Access>, role: Role, path: &str) -> Response {
+    match access.and_then(|a| a.name.as_ref()) {
+        Some(name) => {
+            warn!("Refused {name} access to {path}, which needs the {role} role");
+            (
+                StatusCode::FORBIDDEN,
+                format!("Forbidden, this needs a token with the {role} role"),
+            )
+                .into_response()
+        }
+        None => {
+            warn!("Refused unauthorized request to {path}");
+            // The dashboard talks to the admin API through fetch, which would
+            // make browsers ask for a name and password on a Basic challenge.
+            let challenge = if role == Role::Admin {
+                "Bearer"
+            } else {
+                Credentials::challenge(None)
+            };
+            unauthorized(challenge)
+        }
+    }
+}
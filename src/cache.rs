@@ -1,20 +1,80 @@
 use hashbrown::HashMap;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
 
 /// The callback to run when a item goes out of the cache.
-pub type Callback = Box<dyn Fn(String) + Send + 'static>;
+pub type Callback = Box<dyn Fn(String) + Send + Sync + 'static>;
 
 /// A piece of data for usage in the [Cache](`Cache`).
 struct Data {
     /// The path to the directory holding the code.
     path: PathBuf,
 
-    /// The point at which the [Data] got created.
+    /// The point at which the [Data] got created, or last accessed.
     creation: Instant,
+
+    /// The point at which the [Data] got inserted.
+    inserted: Instant,
+
+    /// How many times the [Data] got looked up.
+    hits: u64,
+}
+
+/// A piece of [Data](`Data`) as saved to disk by [save](`Cache::save`).
+#[derive(Debug, Deserialize, Serialize)]
+struct SavedData {
+    key: String,
+    path: PathBuf,
+    /// How long ago the data got created (or last accessed) in seconds.
+    age: u64,
+    /// How long ago the data got inserted in seconds.
+    #[serde(default)]
+    inserted: u64,
+    #[serde(default)]
+    hits: u64,
+}
+
+/// A piece of [Data](`Data`) as shown by [entries](`Cache::entries`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Entry {
+    pub key: String,
+    pub path: PathBuf,
+    /// The size of the file at `path` in bytes, 0 when it is gone.
+    pub size: u64,
+    /// How long ago it got inserted in seconds.
+    pub age: u64,
+    /// How long ago it got last looked up (or inserted) in seconds,
+    /// the timeout counts from then.
+    pub last_access: u64,
+    /// How many times it got looked up.
+    pub hits: u64,
+}
+
+/// How a [Cache](`Cache`) has been used since it got created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Lookups which found their [Data](`Data`).
+    pub hits: u64,
+    /// Lookups which did not.
+    pub misses: u64,
+    /// Pieces of [Data](`Data`) which went out of the cache because of the timeout.
+    pub evictions: u64,
+    /// How many pieces of [Data](`Data`) are in the cache right now.
+    pub entries: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
 }
 
 type DB = Arc<Mutex<HashMap<String, Data>>>;
@@ -24,6 +84,16 @@ type DB = Arc<Mutex<HashMap<String, Data>>>;
 pub struct Cache {
     /// The [hashmap](`hashbrown::HashMap`) for storing the [Data](`Data`) in.
     hmap: DB,
+
+    /// How long each [Data](`Data`) lives in nanoseconds, shared with the loop erasing them
+    /// so that it can be changed while running.
+    timeout: Arc<AtomicU64>,
+
+    /// Shared with the loop erasing [Data](`Data`), which counts the evictions.
+    counters: Arc<Counters>,
+
+    /// Shared with the loop erasing [Data](`Data`), for [remove](`Cache::remove`) to run it too.
+    callback: Arc<Option<Callback>>,
 }
 
 impl Cache {
@@ -33,20 +103,32 @@ impl Cache {
     /// * callback: A optional [Callback](`Callback`) for executing some code when a piece of [Data](`Data`) goes out of the [Cache].
     pub async fn new(data_timeout: Duration, callback: Option<Callback>) -> Self {
         let hmap: DB = Arc::new(Mutex::new(HashMap::new()));
+        let timeout = Arc::new(AtomicU64::new(data_timeout.as_nanos() as u64));
+        let counters = Arc::new(Counters::default());
+        let callback = Arc::new(callback);
 
         // For each new cache, spawn a loop which erases all data when it excedes the deadlines.
-        // Only do this when the duration is greater than 0, 0 should mean no timeout.
-        if !data_timeout.is_zero() {
+        // A duration of 0 means no timeout, the loop idles until it gets changed.
+        {
             let h = hmap.clone();
+            let t = timeout.clone();
+            let c = counters.clone();
+            let callback = callback.clone();
             tokio::spawn(async move {
                 // Vec for storing the dead (timed out) keys from the hashmap.
                 // This looks really weird since we allocate the vec at new-time
                 // when the hashmap does not have any elements in it...?
-                // We do need a auxillary data structure though since we cant remove 
+                // We do need a auxillary data structure though since we cant remove
                 // things from the map while iterating over the it.
                 let mut dead: Vec<String> = Vec::with_capacity(h.lock().unwrap().len());
 
                 loop {
+                    let data_timeout = Duration::from_nanos(t.load(Ordering::Relaxed));
+                    if data_timeout.is_zero() {
+                        tokio::time::sleep(Duration::new(1, 0)).await;
+                        continue;
+                    }
+
                     // Let the function loop on a interval.
                     tokio::time::sleep(Duration::new(0, 1)).await;
 
@@ -63,11 +145,12 @@ impl Cache {
 
                     // Remove all the now dead (timed out) pieces of data from the hashmap.
                     for key in &dead {
-                        if let Some(ref f) = callback {
+                        if let Some(f) = callback.as_ref() {
                             f(key.clone());
                         }
 
                         map.remove_entry(key);
+                        c.evictions.fetch_add(1, Ordering::Relaxed);
                     }
 
                     // Reset the array for storing new dead data.
@@ -76,7 +159,34 @@ impl Cache {
             });
         }
 
-        Cache { hmap }
+        Cache {
+            hmap,
+            timeout,
+            counters,
+            callback,
+        }
+    }
+
+    /// Changes how long each [Data](`Data`) lives, 0 for no timeout.
+    /// Applies to data already in the [Cache](`Cache`) too.
+    pub fn set_timeout(&self, data_timeout: Duration) {
+        self.timeout
+            .store(data_timeout.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// How long each [Data](`Data`) lives, 0 for no timeout.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_nanos(self.timeout.load(Ordering::Relaxed))
+    }
+
+    /// How the [Cache](`Cache`) has been used so far.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            entries: self.hmap.lock().unwrap().len(),
+        }
     }
 
     /// Gets the item matching [k] from the [Cache](`Cache`).
@@ -85,21 +195,133 @@ impl Cache {
         {
             let mut hmap = self.hmap.lock().unwrap();
             let v = hmap.get_mut(k);
-            if let Some(mut data) = v {
+            let counter = if let Some(data) = v {
                 data.creation = Instant::now();
-            }
+                data.hits += 1;
+                &self.counters.hits
+            } else {
+                &self.counters.misses
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
         }
 
         self.hmap.lock().unwrap().get(k).map(|v| v.path.clone())
     }
 
+    /// Whether [k] is in the [Cache](`Cache`), without counting as a access.
+    pub fn contains(&self, k: &str) -> bool {
+        self.hmap.lock().unwrap().contains_key(k)
+    }
+
     /// Inserts a [k] and [v] into the [Cache](`Cache`).
     pub fn insert(&mut self, k: String, v: PathBuf) {
+        let now = Instant::now();
         let d = Data {
             path: v,
-            creation: Instant::now(),
+            creation: now,
+            inserted: now,
+            hits: 0,
         };
 
         self.hmap.lock().unwrap().insert(k, d);
     }
+
+    /// Everything in the [Cache](`Cache`), sorted by key.
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries: Vec<Entry> = self
+            .hmap
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, data)| Entry {
+                key: key.clone(),
+                path: data.path.clone(),
+                size: 0,
+                age: data.inserted.elapsed().as_secs(),
+                last_access: data.creation.elapsed().as_secs(),
+                hits: data.hits,
+            })
+            .collect();
+
+        // Only looked at once the lock is let go of, what is cached are single files.
+        for entry in &mut entries {
+            entry.size = fs::metadata(&entry.path).map(|m| m.len()).unwrap_or(0);
+        }
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+
+    /// Takes `k` out of the [Cache](`Cache`) right away, running the [Callback](`Callback`)
+    /// like a timeout would. Returns whether it was there.
+    pub fn remove(&self, k: &str) -> bool {
+        let removed = self.hmap.lock().unwrap().remove(k).is_some();
+        if removed {
+            if let Some(f) = self.callback.as_ref() {
+                f(k.to_string());
+            }
+        }
+        removed
+    }
+
+    /// Takes everything out of the [Cache](`Cache`), returning the keys removed.
+    pub fn clear(&self) -> Vec<String> {
+        let keys: Vec<String> = self.hmap.lock().unwrap().keys().cloned().collect();
+        keys.into_iter().filter(|k| self.remove(k)).collect()
+    }
+
+    /// Writes the contents of the [Cache](`Cache`) to `file`, for [restore](`Cache::restore`)
+    /// to pick them up again after restarting.
+    pub fn save(&self, file: &Path) -> io::Result<()> {
+        let saved: Vec<SavedData> = self
+            .hmap
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, data)| SavedData {
+                key: key.clone(),
+                path: data.path.clone(),
+                age: data.creation.elapsed().as_secs(),
+                inserted: data.inserted.elapsed().as_secs(),
+                hits: data.hits,
+            })
+            .collect();
+
+        fs::write(file, serde_json::to_vec(&saved)?)
+    }
+
+    /// Inserts the contents saved to `file` by [save](`Cache::save`), keeping how long ago
+    /// each piece of [Data](`Data`) got created. Data which no longer exists on disk is skipped.
+    ///
+    /// The file is removed afterwards and the keys inserted are returned.
+    pub fn restore(&mut self, file: &Path) -> io::Result<Vec<String>> {
+        let saved: Vec<SavedData> = serde_json::from_slice(&fs::read(file)?)?;
+        fs::remove_file(file)?;
+
+        let mut hmap = self.hmap.lock().unwrap();
+        let mut keys = Vec::with_capacity(saved.len());
+        for data in saved {
+            if !data.path.exists() {
+                continue;
+            }
+
+            let ago = |secs| {
+                Instant::now()
+                    .checked_sub(Duration::from_secs(secs))
+                    .unwrap_or_else(Instant::now)
+            };
+            hmap.insert(
+                data.key.clone(),
+                Data {
+                    path: data.path,
+                    creation: ago(data.age),
+                    // Caches saved before insertion times were recorded fall back to the age.
+                    inserted: ago(data.inserted.max(data.age)),
+                    hits: data.hits,
+                },
+            );
+            keys.push(data.key);
+        }
+
+        Ok(keys)
+    }
 }
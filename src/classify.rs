@@ -0,0 +1,125 @@
+use std::{fmt, fs, io::Read, path::Path};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    activity::{Event, Record},
+    routes::{self, PostData},
+};
+
+/// A captured [PostData](`PostData`) payload and the target triple it should be guessed as.
+#[derive(Debug, Deserialize)]
+pub struct Case {
+    #[serde(flatten)]
+    pub payload: PostData,
+    /// Not set when the payload is only to be classified, set to `null` for
+    /// payloads which no target triple should be guessed for.
+    #[serde(default, deserialize_with = "present")]
+    pub expected: Option<Option<String>>,
+}
+
+/// Tells a `null` apart from a missing field.
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<String>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// The payloads of a file like `data.json`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Document {
+    Fixture { post_data: Vec<Case> },
+    List(Vec<Case>),
+    Single(Case),
+}
+
+/// Reads payloads from `path`, or from stdin when it is `-`.
+///
+/// Takes a JSON document (a single payload, a list of them or `{"post_data": [...]}` like
+/// `data.json`) or JSONL, one payload per line. In JSONL the guess events of the
+/// [ActivityLog](`crate::activity::ActivityLog`) are replayed expecting what got guessed at
+/// the time, any other events are skipped.
+pub fn load(path: &Path) -> Result<Vec<Case>, String> {
+    let mut contents = String::new();
+    if path == Path::new("-") {
+        std::io::stdin()
+            .read_to_string(&mut contents)
+            .map_err(|e| format!("Failed to read stdin: {e}"))?;
+    } else {
+        contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+    }
+
+    if let Ok(document) = serde_json::from_str::<Document>(&contents) {
+        return Ok(match document {
+            Document::Fixture { post_data } => post_data,
+            Document::List(cases) => cases,
+            Document::Single(case) => vec![case],
+        });
+    }
+
+    let mut cases = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Ok(record) = serde_json::from_str::<Record>(line) {
+            if let Event::Guess {
+                os,
+                os_version,
+                user_agent,
+                target,
+                ..
+            } = record.event
+            {
+                cases.push(Case {
+                    payload: PostData::new(os, os_version, user_agent),
+                    expected: Some(target),
+                });
+            }
+            continue;
+        }
+
+        let case =
+            serde_json::from_str(line).map_err(|e| format!("{path:?} line {}: {e}", i + 1))?;
+        cases.push(case);
+    }
+
+    Ok(cases)
+}
+
+/// A [Case](`Case`) which did not get guessed as expected.
+#[derive(Debug)]
+pub struct Mismatch<'a> {
+    pub case: &'a Case,
+    pub guessed: Option<String>,
+}
+
+impl fmt::Display for Mismatch<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let triple = |t: &Option<String>| t.clone().unwrap_or_else(|| "nothing".to_string());
+        let expected = self.case.expected.clone().flatten();
+        let payload = &self.case.payload;
+        write!(
+            f,
+            "expected {}, guessed {} for os {:?}, os_version {:?}, user_agent {:?}",
+            triple(&expected),
+            triple(&self.guessed),
+            payload.os,
+            payload.os_version,
+            payload.user_agent
+        )
+    }
+}
+
+/// Runs every case with an expectation through the detection of `get_target`,
+/// returning the ones which got guessed differently.
+pub fn replay(cases: &[Case]) -> Vec<Mismatch<'_>> {
+    cases
+        .iter()
+        .filter_map(|case| {
+            let expected = case.expected.as_ref()?;
+            let guessed = routes::guess_target_triple(&case.payload).ok();
+            (guessed != *expected).then_some(Mismatch { case, guessed })
+        })
+        .collect()
+}
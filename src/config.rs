@@ -0,0 +1,899 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    git::{self, REDACTED},
+    history::Retention,
+    project::{self, ProjectSettings},
+};
+
+/// The program compiling the repos.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Cross compiles through `cross`, which needs docker or podman.
+    #[default]
+    Cross,
+    /// Plain `cargo`, only works for targets the host has a toolchain and linker for.
+    Cargo,
+}
+
+impl Backend {
+    /// The executable to run for compiling.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Backend::Cross => "cross",
+            Backend::Cargo => "cargo",
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cross" => Ok(Backend::Cross),
+            "cargo" => Ok(Backend::Cargo),
+            _ => Err(format!(
+                "Unknown backend: {s}, expected \"cross\" or \"cargo\""
+            )),
+        }
+    }
+}
+
+/// How log lines get written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// A JSON object per line, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Unknown log format: {s}, expected \"text\" or \"json\""
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+    /// How long compiled targets stay in cache in seconds, 0 keeps them forever.
+    pub timeout: u64,
+    /// Which revisions of each target to keep.
+    pub keep: Retention,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            timeout: 1024,
+            keep: Retention::Last(2),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// How many targets may be compiled at the same time across all projects, 0 for no limit.
+    pub max_builds: usize,
+    /// How long a single build may take in seconds before it is aborted, 0 for no limit.
+    pub build_timeout: u64,
+    /// How long to wait for downloads and builds in progress to finish when shutting down
+    /// in seconds before aborting them, 0 for no limit.
+    pub drain_timeout: u64,
+    /// How much space has to be free on the disk holding the compilation directory in
+    /// megabytes for the server to report itself as ready, 0 to not check.
+    pub min_free_space: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_builds: 0,
+            build_timeout: 0,
+            drain_timeout: 30,
+            min_free_space: 1024,
+        }
+    }
+}
+
+impl Limits {
+    pub fn build_timeout(&self) -> Option<Duration> {
+        (self.build_timeout > 0).then(|| Duration::from_secs(self.build_timeout))
+    }
+
+    pub fn drain_timeout(&self) -> Option<Duration> {
+        (self.drain_timeout > 0).then(|| Duration::from_secs(self.drain_timeout))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    /// The PEM encoded certificate chain, serving HTTPS when set together with `key`.
+    pub cert: Option<PathBuf>,
+    /// The PEM encoded private key of the certificate.
+    pub key: Option<PathBuf>,
+    /// The port to listen on for plain HTTP requests and redirect them to HTTPS, 0 for none.
+    pub redirect_port: u16,
+}
+
+impl TlsSettings {
+    /// The certificate and key paths, when serving HTTPS.
+    pub fn files(&self) -> Option<(&Path, &Path)> {
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActivitySettings {
+    /// Whether to record guesses, downloads and builds at all.
+    pub enabled: bool,
+    /// The JSONL file to record them in, `<path>/activity.jsonl` when not set.
+    pub file: Option<PathBuf>,
+    /// The size in megabytes after which the file gets rotated.
+    pub max_size: u64,
+    /// How many rotated files to keep around, `<file>.1` being the most recent one.
+    pub keep: usize,
+}
+
+impl Default for ActivitySettings {
+    fn default() -> Self {
+        ActivitySettings {
+            enabled: true,
+            file: None,
+            max_size: 10,
+            keep: 5,
+        }
+    }
+}
+
+impl ActivitySettings {
+    /// The file to record in, when `path` is the directory `repo_to_compile` gets placed in.
+    pub fn file(&self, path: &Path) -> PathBuf {
+        self.file
+            .clone()
+            .unwrap_or_else(|| path.join("activity.jsonl"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    pub format: LogFormat,
+    /// The OTLP/HTTP endpoint of a OpenTelemetry collector to export spans to,
+    /// such as `http://localhost:4318`. Nothing gets exported when not set.
+    pub otlp_endpoint: Option<String>,
+    /// The `service.name` the spans get exported under.
+    pub service_name: String,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            format: LogFormat::default(),
+            otlp_endpoint: None,
+            service_name: "gload".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSettings {
+    /// The bearer token the admin API under `/admin`, `/metrics` and `/activity` are served to.
+    /// They are always there, but refuse every request while neither this nor a token with
+    /// the admin role in `auth.tokens_file` is set.
+    pub token: Option<String>,
+    /// Whether to serve the dashboard page at `/admin` along with the admin API.
+    pub dashboard: bool,
+}
+
+impl Default for AdminSettings {
+    fn default() -> Self {
+        AdminSettings {
+            token: None,
+            dashboard: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// The tokens clients authenticate with, see [auth](`crate::auth`). Without it
+    /// anyone may download and build, only the admin API is protected.
+    pub tokens_file: Option<PathBuf>,
+    /// Whether targets which are built already may be downloaded without a token.
+    pub public_downloads: bool,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            tokens_file: None,
+            public_downloads: true,
+        }
+    }
+}
+
+/// Credentials for cloning private repos, see [git](`crate::git`).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitSettings {
+    /// A private key (such as a deploy key) for cloning over SSH.
+    pub ssh_key: Option<PathBuf>,
+    /// The hosts to trust when cloning over SSH, in the format of `~/.ssh/known_hosts`.
+    /// Hosts not in it are refused rather than trusted on first use.
+    pub known_hosts: Option<PathBuf>,
+    /// A access token for cloning over HTTPS.
+    pub token: Option<String>,
+    /// The user name the token is sent with, which most hosts dont care about.
+    pub username: String,
+}
+
+impl Default for GitSettings {
+    fn default() -> Self {
+        GitSettings {
+            ssh_key: None,
+            known_hosts: None,
+            token: None,
+            username: "x-access-token".to_string(),
+        }
+    }
+}
+
+impl GitSettings {
+    /// Every problem with these settings, prefixed by `owner`.
+    fn check(&self, owner: &str, errors: &mut Vec<String>) {
+        for (key, file) in [
+            ("ssh_key", &self.ssh_key),
+            ("known_hosts", &self.known_hosts),
+        ] {
+            if let Some(file) = file.as_ref().filter(|f| !f.is_file()) {
+                errors.push(format!("{owner}git.{key}: {file:?} does not exist"));
+            }
+        }
+        if self.token.as_ref().is_some_and(|t| t.trim().is_empty()) {
+            errors.push(format!("{owner}git.token: must not be empty"));
+        }
+    }
+}
+
+/// How often clients may download and build, 0 for no limit.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSettings {
+    /// Downloads of targets which are built already per client.
+    pub downloads_per_minute: u32,
+    /// Downloads of targets which have to be built first per client.
+    pub builds_per_hour: u32,
+    /// Downloads of targets which have to be built first across all clients.
+    pub global_builds_per_hour: u32,
+    /// Whether to tell clients apart by the last address in `X-Forwarded-For`, the one the
+    /// proxy added, only for when gload is behind a reverse proxy appending to it.
+    pub trust_forwarded_for: bool,
+}
+
+/// Everything configurable about gload.
+///
+/// Settings are resolved from (in increasing order of precedence) the defaults, the
+/// configuration file, `GLOAD_*` environment variables and the command line flags.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// The address to listen on.
+    pub bind: IpAddr,
+    pub port: u16,
+    /// The directory to place `repo_to_compile` in.
+    pub path: PathBuf,
+    pub debug: bool,
+    pub backend: Backend,
+    /// The target triples which may be compiled, all of them when not set.
+    /// Projects can set their own.
+    pub targets: Option<Vec<String>>,
+
+    /// The repo to serve from the root, mutually exclusive with `projects`.
+    pub repo: Option<String>,
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    /// The name of the binary to return.
+    pub name: Option<String>,
+    /// Extra files to put in archives.
+    pub include: Vec<String>,
+
+    pub cache: CacheSettings,
+    pub limits: Limits,
+    pub tls: TlsSettings,
+    pub activity: ActivitySettings,
+    pub log: LogSettings,
+    pub admin: AdminSettings,
+    pub auth: AuthSettings,
+    pub rate_limit: RateLimitSettings,
+    /// Projects can set their own.
+    pub git: GitSettings,
+
+    /// The repos to serve under `/p/<name>`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub projects: BTreeMap<String, ProjectSettings>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 3000,
+            path: PathBuf::from("./"),
+            debug: false,
+            backend: Backend::default(),
+            targets: None,
+            repo: None,
+            git_ref: None,
+            name: None,
+            include: Vec::new(),
+            cache: CacheSettings::default(),
+            limits: Limits::default(),
+            tls: TlsSettings::default(),
+            activity: ActivitySettings::default(),
+            log: LogSettings::default(),
+            admin: AdminSettings::default(),
+            auth: AuthSettings::default(),
+            rate_limit: RateLimitSettings::default(),
+            git: GitSettings::default(),
+            projects: BTreeMap::new(),
+        }
+    }
+}
+
+/// The command line flags which override a setting, by their id and the key of the setting.
+const FLAG_SETTINGS: [(&str, &str); 16] = [
+    ("repo", "repo"),
+    ("ref", "ref"),
+    ("timeout", "cache.timeout"),
+    ("path", "path"),
+    ("port", "port"),
+    ("bind", "bind"),
+    ("backend", "backend"),
+    ("name", "name"),
+    ("keep", "cache.keep"),
+    ("max-builds", "limits.max_builds"),
+    ("drain-timeout", "limits.drain_timeout"),
+    ("tls-cert", "tls.cert"),
+    ("tls-key", "tls.key"),
+    ("redirect-port", "tls.redirect_port"),
+    ("log-format", "log.format"),
+    ("otlp-endpoint", "log.otlp_endpoint"),
+];
+
+impl Settings {
+    /// The keys which can be overridden through [set](`Settings::set`), each of them can be set
+    /// through the environment variable `GLOAD_<KEY>` with dots replaced by underscores.
+    pub const KEYS: [&'static str; 38] = [
+        "bind",
+        "port",
+        "path",
+        "debug",
+        "backend",
+        "targets",
+        "repo",
+        "ref",
+        "name",
+        "include",
+        "cache.timeout",
+        "cache.keep",
+        "limits.max_builds",
+        "limits.build_timeout",
+        "limits.drain_timeout",
+        "limits.min_free_space",
+        "tls.cert",
+        "tls.key",
+        "tls.redirect_port",
+        "activity.enabled",
+        "activity.file",
+        "activity.max_size",
+        "activity.keep",
+        "log.format",
+        "log.otlp_endpoint",
+        "log.service_name",
+        "admin.token",
+        "admin.dashboard",
+        "auth.tokens_file",
+        "auth.public_downloads",
+        "rate_limit.downloads_per_minute",
+        "rate_limit.builds_per_hour",
+        "rate_limit.global_builds_per_hour",
+        "rate_limit.trust_forwarded_for",
+        "git.ssh_key",
+        "git.known_hosts",
+        "git.token",
+        "git.username",
+    ];
+
+    /// Resolves the settings from the configuration file, the environment and the
+    /// command line flags in `matches`, in that order of precedence.
+    pub fn resolve(matches: &ArgMatches) -> Result<Self, String> {
+        let mut settings = match Settings::config_file(matches) {
+            Some(path) => Settings::read(&path)?,
+            None => Settings::default(),
+        };
+
+        settings.apply_env(std::env::vars())?;
+
+        for (id, key) in FLAG_SETTINGS {
+            if let Some(value) = matches.get_one::<String>(id) {
+                settings
+                    .set(key, value)
+                    .map_err(|e| format!("--{id}: {e}"))?;
+            }
+        }
+        if matches.contains_id("debug") {
+            settings.debug = true;
+        }
+        if let Some(files) = matches.get_many::<String>("include") {
+            settings.include = files.cloned().collect();
+        }
+        if let Some(path) = matches.get_one::<String>("projects") {
+            settings.projects = project::read_projects(&PathBuf::from(path))?;
+        }
+
+        Ok(settings)
+    }
+
+    /// The configuration file given through `--config` or `GLOAD_CONFIG`.
+    pub fn config_file(matches: &ArgMatches) -> Option<PathBuf> {
+        matches
+            .get_one::<String>("config")
+            .cloned()
+            .or_else(|| std::env::var("GLOAD_CONFIG").ok())
+            .map(PathBuf::from)
+    }
+
+    /// Reads the configuration file at `path`, YAML when it ends in `.yaml` or `.yml`
+    /// and TOML otherwise.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => Settings::parse_yaml(&contents),
+            _ => Settings::parse_toml(&contents),
+        }
+        .map_err(|e| format!("Invalid configuration in {path:?}: {e}"))
+    }
+
+    pub fn parse_toml(contents: &str) -> Result<Self, String> {
+        toml::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    pub fn parse_yaml(contents: &str) -> Result<Self, String> {
+        serde_yaml::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    /// The environment variable overriding `key`.
+    pub fn env_var(key: &str) -> String {
+        format!("GLOAD_{}", key.replace('.', "_").to_uppercase())
+    }
+
+    /// Applies the `GLOAD_*` environment variables from `vars` on top of these settings.
+    pub fn apply_env(
+        &mut self,
+        vars: impl Iterator<Item = (String, String)>,
+    ) -> Result<(), String> {
+        let vars: BTreeMap<String, String> = vars.collect();
+        for key in Settings::KEYS {
+            let var = Settings::env_var(key);
+            if let Some(value) = vars.get(&var) {
+                self.set(key, value).map_err(|e| format!("{var}: {e}"))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the setting `key` (one of [KEYS](`Settings::KEYS`)) from its string form.
+    /// Lists are comma separated.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn parse<T: FromStr>(value: &str) -> Result<T, String>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value
+                .trim()
+                .parse::<T>()
+                .map_err(|e| format!("Invalid value {value:?}: {e}"))
+        }
+
+        let boolean = |value: &str| -> Result<bool, String> {
+            match value.trim() {
+                "1" | "true" | "yes" | "on" => Ok(true),
+                "0" | "false" | "no" | "off" | "" => Ok(false),
+                _ => Err(format!("Invalid value {value:?}: expected true or false")),
+            }
+        };
+
+        let list = |value: &str| -> Vec<String> {
+            value
+                .split(',')
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect()
+        };
+
+        match key {
+            "bind" => self.bind = parse(value)?,
+            "port" => self.port = parse(value)?,
+            "path" => self.path = PathBuf::from(value),
+            "debug" => self.debug = boolean(value)?,
+            "backend" => self.backend = parse(value)?,
+            "targets" => self.targets = Some(list(value)),
+            "repo" => self.repo = Some(value.to_string()),
+            "ref" => self.git_ref = Some(value.to_string()),
+            "name" => self.name = Some(value.to_string()),
+            "include" => self.include = list(value),
+            "cache.timeout" => self.cache.timeout = parse(value)?,
+            "cache.keep" => self.cache.keep = parse(value)?,
+            "limits.max_builds" => self.limits.max_builds = parse(value)?,
+            "limits.build_timeout" => self.limits.build_timeout = parse(value)?,
+            "limits.drain_timeout" => self.limits.drain_timeout = parse(value)?,
+            "limits.min_free_space" => self.limits.min_free_space = parse(value)?,
+            "tls.cert" => self.tls.cert = Some(PathBuf::from(value)),
+            "tls.key" => self.tls.key = Some(PathBuf::from(value)),
+            "tls.redirect_port" => self.tls.redirect_port = parse(value)?,
+            "activity.enabled" => self.activity.enabled = boolean(value)?,
+            "activity.file" => self.activity.file = Some(PathBuf::from(value)),
+            "activity.max_size" => self.activity.max_size = parse(value)?,
+            "activity.keep" => self.activity.keep = parse(value)?,
+            "log.format" => self.log.format = parse(value)?,
+            "log.otlp_endpoint" => self.log.otlp_endpoint = Some(value.to_string()),
+            "log.service_name" => self.log.service_name = value.to_string(),
+            "admin.token" => self.admin.token = Some(value.to_string()),
+            "admin.dashboard" => self.admin.dashboard = boolean(value)?,
+            "auth.tokens_file" => self.auth.tokens_file = Some(PathBuf::from(value)),
+            "auth.public_downloads" => self.auth.public_downloads = boolean(value)?,
+            "rate_limit.downloads_per_minute" => {
+                self.rate_limit.downloads_per_minute = parse(value)?
+            }
+            "rate_limit.builds_per_hour" => self.rate_limit.builds_per_hour = parse(value)?,
+            "rate_limit.global_builds_per_hour" => {
+                self.rate_limit.global_builds_per_hour = parse(value)?
+            }
+            "rate_limit.trust_forwarded_for" => {
+                self.rate_limit.trust_forwarded_for = boolean(value)?
+            }
+            "git.ssh_key" => self.git.ssh_key = Some(PathBuf::from(value)),
+            "git.known_hosts" => self.git.known_hosts = Some(PathBuf::from(value)),
+            "git.token" => self.git.token = Some(value.to_string()),
+            "git.username" => self.git.username = value.to_string(),
+            _ => return Err(format!("Unknown setting: {key}")),
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the settings make sense together, returns every problem found.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        if self.port == 0 {
+            errors.push("port: must not be 0".to_string());
+        }
+        if !self.path.is_dir() {
+            errors.push(format!("path: {:?} is not a directory", self.path));
+        }
+
+        match (&self.repo, self.projects.is_empty()) {
+            (None, true) => {
+                errors.push("no repo to serve, set repo or configure projects".to_string())
+            }
+            (Some(_), false) => errors.push("repo and projects can not both be set".to_string()),
+            _ => {}
+        }
+
+        let mut check_targets = |owner: &str, targets: &Option<Vec<String>>| match targets {
+            Some(t) if t.is_empty() => {
+                errors.push(format!("{owner}targets: allows no targets at all"))
+            }
+            Some(t) => {
+                for target in t.iter().filter(|t| !is_target_triple(t)) {
+                    errors.push(format!("{owner}targets: {target:?} is not a target triple"));
+                }
+            }
+            None => {}
+        };
+        check_targets("", &self.targets);
+        for (name, project) in &self.projects {
+            check_targets(&format!("projects.{name}."), &project.targets);
+        }
+
+        for (name, project) in &self.projects {
+            if !project::is_valid_name(name) {
+                errors.push(format!(
+                    "projects.{name}: only letters, digits, '-' and '_' are allowed in names"
+                ));
+            }
+            if project.source.is_empty() {
+                errors.push(format!("projects.{name}.source: must not be empty"));
+            }
+            if let Some(git) = &project.git {
+                git.check(&format!("projects.{name}."), &mut errors);
+            }
+        }
+
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => {
+                for file in [cert, key].into_iter().filter(|f| !f.is_file()) {
+                    errors.push(format!("tls: {file:?} does not exist"));
+                }
+            }
+            (None, None) => {
+                if self.tls.redirect_port != 0 {
+                    errors.push(
+                        "tls.redirect_port: redirecting to HTTPS needs tls.cert and tls.key"
+                            .to_string(),
+                    );
+                }
+            }
+            _ => errors.push("tls: both tls.cert and tls.key have to be set".to_string()),
+        }
+        if self.tls.redirect_port != 0 && self.tls.redirect_port == self.port {
+            errors.push("tls.redirect_port: must differ from port".to_string());
+        }
+
+        if self.admin.token.as_ref().is_some_and(|t| t.len() < 16) {
+            errors.push("admin.token: must be at least 16 characters".to_string());
+        }
+
+        self.git.check("", &mut errors);
+
+        if let Some(file) = &self.auth.tokens_file {
+            if !file.is_file() {
+                errors.push(format!("auth.tokens_file: {file:?} does not exist"));
+            }
+        }
+
+        if let Some(endpoint) = &self.log.otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                errors.push(format!(
+                    "log.otlp_endpoint: {endpoint:?} is not a http:// or https:// URL"
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// The projects to serve by name, either the `repo` or the `projects`.
+    pub fn served_projects(&self) -> Vec<(String, ProjectSettings)> {
+        match self.root_project() {
+            Some(root) => vec![(project::name_from_source(&root.source), root)],
+            None => self
+                .projects
+                .iter()
+                .map(|(name, p)| (name.clone(), p.clone()))
+                .collect(),
+        }
+    }
+
+    /// The settings which differ between these and the `new` settings that can be applied
+    /// while running, see [Project::apply](`crate::project::Project::apply`).
+    pub fn live_changes(&self, new: &Settings) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut changed = |key: &str, differs: bool| {
+            if differs {
+                changes.push(key.to_string());
+            }
+        };
+
+        changed("targets", self.targets != new.targets);
+        changed("name", self.name != new.name);
+        changed("include", self.include != new.include);
+        changed("cache.timeout", self.cache.timeout != new.cache.timeout);
+        changed(
+            "limits.build_timeout",
+            self.limits.build_timeout != new.limits.build_timeout,
+        );
+        changed("admin.token", self.admin.token != new.admin.token);
+        changed(
+            "auth.tokens_file",
+            self.auth.tokens_file != new.auth.tokens_file,
+        );
+        changed(
+            "auth.public_downloads",
+            self.auth.public_downloads != new.auth.public_downloads,
+        );
+        changed("git", self.git != new.git);
+        changed(
+            "rate_limit.downloads_per_minute",
+            self.rate_limit.downloads_per_minute != new.rate_limit.downloads_per_minute,
+        );
+        changed(
+            "rate_limit.builds_per_hour",
+            self.rate_limit.builds_per_hour != new.rate_limit.builds_per_hour,
+        );
+        changed(
+            "rate_limit.global_builds_per_hour",
+            self.rate_limit.global_builds_per_hour != new.rate_limit.global_builds_per_hour,
+        );
+        changed(
+            "rate_limit.trust_forwarded_for",
+            self.rate_limit.trust_forwarded_for != new.rate_limit.trust_forwarded_for,
+        );
+
+        for (name, old) in &self.projects {
+            if let Some(new) = new.projects.get(name) {
+                let key = |field: &str| format!("projects.{name}.{field}");
+                changed(&key("targets"), old.targets != new.targets);
+                changed(&key("binary"), old.binary != new.binary);
+                changed(&key("include"), old.include != new.include);
+                changed(&key("timeout"), old.timeout != new.timeout);
+                changed(&key("git"), old.git != new.git);
+            }
+        }
+
+        changes
+    }
+
+    /// The settings which differ between these and the `new` settings that only take effect
+    /// after restarting.
+    pub fn restart_required(&self, new: &Settings) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut changed = |key: &str, differs: bool| {
+            if differs {
+                changes.push(key.to_string());
+            }
+        };
+
+        changed("bind", self.bind != new.bind);
+        changed("port", self.port != new.port);
+        changed("path", self.path != new.path);
+        changed("debug", self.debug != new.debug);
+        changed("backend", self.backend != new.backend);
+        changed("repo", self.repo != new.repo);
+        changed("ref", self.git_ref != new.git_ref);
+        changed("cache.keep", self.cache.keep != new.cache.keep);
+        // The contents of the certificate get reloaded, not where it is.
+        changed("tls.cert", self.tls.cert != new.tls.cert);
+        changed("tls.key", self.tls.key != new.tls.key);
+        changed(
+            "tls.redirect_port",
+            self.tls.redirect_port != new.tls.redirect_port,
+        );
+        changed(
+            "limits.max_builds",
+            self.limits.max_builds != new.limits.max_builds,
+        );
+        changed(
+            "activity.enabled",
+            self.activity.enabled != new.activity.enabled,
+        );
+        changed("activity.file", self.activity.file != new.activity.file);
+        changed(
+            "activity.max_size",
+            self.activity.max_size != new.activity.max_size,
+        );
+        changed("activity.keep", self.activity.keep != new.activity.keep);
+        changed("log.format", self.log.format != new.log.format);
+        changed(
+            "log.otlp_endpoint",
+            self.log.otlp_endpoint != new.log.otlp_endpoint,
+        );
+        changed(
+            "log.service_name",
+            self.log.service_name != new.log.service_name,
+        );
+        changed(
+            "admin.dashboard",
+            self.admin.dashboard != new.admin.dashboard,
+        );
+        changed(
+            "limits.drain_timeout",
+            self.limits.drain_timeout != new.limits.drain_timeout,
+        );
+        changed(
+            "limits.min_free_space",
+            self.limits.min_free_space != new.limits.min_free_space,
+        );
+
+        let names: BTreeSet<&String> = self.projects.keys().chain(new.projects.keys()).collect();
+        for name in names {
+            let key = |field: &str| format!("projects.{name}.{field}");
+            match (self.projects.get(name), new.projects.get(name)) {
+                (Some(old), Some(new)) => {
+                    changed(&key("source"), old.source != new.source);
+                    changed(&key("ref"), old.git_ref != new.git_ref);
+                    changed(&key("keep"), old.keep != new.keep);
+                }
+                (None, Some(_)) => changed(&format!("projects.{name} (added)"), true),
+                (Some(_), None) => changed(&format!("projects.{name} (removed)"), true),
+                (None, None) => {}
+            }
+        }
+
+        changes
+    }
+
+    /// The settings for the repo served from the root, when not serving `projects`.
+    pub fn root_project(&self) -> Option<ProjectSettings> {
+        self.repo.as_ref().map(|repo| ProjectSettings {
+            source: repo.clone(),
+            git_ref: self.git_ref.clone(),
+            targets: None,
+            binary: self.name.clone(),
+            include: self.include.clone(),
+            timeout: None,
+            keep: None,
+            git: None,
+        })
+    }
+
+    /// The settings as TOML, for `--print-config`. Secrets are left out, see
+    /// [redacted](`Settings::redacted`).
+    pub fn to_toml(&self) -> Result<String, String> {
+        toml::to_string_pretty(&self.redacted()).map_err(|e| e.to_string())
+    }
+
+    /// These settings with the tokens and the passwords in urls replaced by `***`,
+    /// for showing them.
+    pub fn redacted(&self) -> Settings {
+        let mut settings = self.clone();
+        let hide = |token: &mut Option<String>| {
+            if token.is_some() {
+                *token = Some(REDACTED.to_string());
+            }
+        };
+
+        hide(&mut settings.admin.token);
+        hide(&mut settings.git.token);
+        settings.repo = settings.repo.as_deref().map(git::redact);
+        settings.log.otlp_endpoint = settings.log.otlp_endpoint.as_deref().map(git::redact);
+        for project in settings.projects.values_mut() {
+            project.source = git::redact(&project.source);
+            if let Some(git) = &mut project.git {
+                hide(&mut git.token);
+            }
+        }
+        settings
+    }
+}
+
+/// Whether `target` looks like a target triple, `<arch>-<vendor>-<os>[-<env>]`.
+pub(crate) fn is_target_triple(target: &str) -> bool {
+    let parts: Vec<&str> = target.split('-').collect();
+    (2..=4).contains(&parts.len())
+        && parts.iter().all(|p| {
+            !p.is_empty()
+                && p.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        })
+}
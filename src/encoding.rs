@@ -0,0 +1,124 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use tracing::{debug, error};
+
+use crate::util;
+
+/// The content encodings files can be compressed with before being sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Zstd,
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The encodings in order of preference, best compression first.
+    const PREFERENCE: [Encoding; 3] = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+
+    /// The name of the encoding as used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// The extension of the compressed file stored next to the original.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zst",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    /// Picks the best encoding the client accepts according to its `Accept-Encoding` header.
+    pub fn negotiate(accept_encoding: &str) -> Option<Self> {
+        // Collect the accepted encodings with their quality values, `gzip;q=0.5` etc.
+        let accepted: Vec<(&str, f32)> = accept_encoding
+            .split(',')
+            .filter_map(|e| {
+                let mut parts = e.split(';');
+                let name = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((name, quality))
+            })
+            .collect();
+
+        let quality = |name: &str| {
+            accepted
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .or_else(|| accepted.iter().find(|(n, _)| *n == "*"))
+                .map(|(_, q)| *q)
+                .unwrap_or(0.0)
+        };
+
+        // Prefer the better compression unless the client explicitly prefers something else.
+        Encoding::PREFERENCE
+            .iter()
+            .filter(|e| quality(e.name()) > 0.0)
+            .fold(None, |best: Option<Encoding>, e| match best {
+                Some(b) if quality(b.name()) >= quality(e.name()) => Some(b),
+                _ => Some(*e),
+            })
+    }
+}
+
+/// Gets the variant of the file at `path` compressed with `encoding`.
+///
+/// The compressed file is created once and stored next to the original as `<file>.<extension>`,
+/// which means it goes out of cache together with the original.
+pub async fn compressed(path: &Path, encoding: Encoding) -> Result<PathBuf, String> {
+    let mut destination = path.as_os_str().to_owned();
+    destination.push(format!(".{}", encoding.extension()));
+    let destination = PathBuf::from(destination);
+
+    if destination.exists() {
+        return Ok(destination);
+    }
+
+    debug!("Compressing {path:?} with {}", encoding.name());
+    let temporary = util::temporary_path(&destination);
+    let (p, t) = (path.to_path_buf(), temporary.clone());
+    let result = tokio::task::spawn_blocking(move || compress(&p, &t, encoding))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if let Err(e) = result.and_then(|_| fs::rename(&temporary, &destination)) {
+        error!("Failed to compress {path:?} with {}: {e}", encoding.name());
+        let _ = fs::remove_file(&temporary);
+        return Err(format!("Failed to compress {path:?}!"));
+    }
+
+    Ok(destination)
+}
+
+fn compress(source: &Path, destination: &Path, encoding: Encoding) -> io::Result<()> {
+    let mut source = File::open(source)?;
+    let destination = File::create(destination)?;
+
+    match encoding {
+        Encoding::Zstd => zstd::stream::copy_encode(source, destination, 19),
+        Encoding::Brotli => {
+            let mut writer = brotli::CompressorWriter::new(destination, 4096, 9, 22);
+            io::copy(&mut source, &mut writer)?;
+            writer.flush()
+        }
+        Encoding::Gzip => {
+            let mut writer = GzEncoder::new(destination, Compression::best());
+            io::copy(&mut source, &mut writer)?;
+            writer.finish().map(|_| ())
+        }
+    }
+}
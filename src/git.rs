@@ -0,0 +1,119 @@
+use std::{path::Path, process::Stdio};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::process::Command;
+
+use crate::config::GitSettings;
+
+/// What secrets get replaced with in logs and errors.
+pub const REDACTED: &str = "***";
+
+/// A `git` command fetching with `credentials`, which never waits for someone to type
+/// in a password.
+///
+/// The token goes in as a `Authorization` header for the host of `origin_url` only, through
+/// the environment so that it neither shows up in the process list nor gets saved in the
+/// clone. The SSH key and known hosts go in through `GIT_SSH_COMMAND`.
+pub fn command(origin_url: &str, credentials: &GitSettings) -> Command {
+    let mut git = Command::new("git");
+    git.env("GIT_TERMINAL_PROMPT", "0").stdin(Stdio::null());
+
+    if let Some(ssh) = ssh_command(credentials) {
+        git.env("GIT_SSH_COMMAND", ssh);
+    }
+    if let (Some(header), Some(origin)) = (authorization(credentials), http_origin(origin_url)) {
+        git.env("GIT_CONFIG_COUNT", "1")
+            .env("GIT_CONFIG_KEY_0", format!("http.{origin}.extraHeader"))
+            .env("GIT_CONFIG_VALUE_0", format!("Authorization: {header}"));
+    }
+    git
+}
+
+/// The ssh command to fetch with, when there is a key or known hosts to use.
+pub fn ssh_command(credentials: &GitSettings) -> Option<String> {
+    if credentials.ssh_key.is_none() && credentials.known_hosts.is_none() {
+        return None;
+    }
+
+    // Fail rather than ask about passphrases or unknown hosts.
+    let mut ssh = "ssh -o BatchMode=yes".to_string();
+    if let Some(key) = &credentials.ssh_key {
+        ssh.push_str(&format!(" -o IdentitiesOnly=yes -i {}", quote(key)));
+    }
+    if let Some(known_hosts) = &credentials.known_hosts {
+        ssh.push_str(&format!(
+            " -o StrictHostKeyChecking=yes -o UserKnownHostsFile={}",
+            quote(known_hosts)
+        ));
+    }
+    Some(ssh)
+}
+
+/// Quotes `path` for the shell `GIT_SSH_COMMAND` is run by.
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// The value of the `Authorization` header sending the token.
+fn authorization(credentials: &GitSettings) -> Option<String> {
+    let token = credentials.token.as_ref()?;
+    let basic = STANDARD.encode(format!("{}:{token}", credentials.username));
+    Some(format!("Basic {basic}"))
+}
+
+/// `https://host/` of a https url, what `http.<url>.*` settings are matched against.
+fn http_origin(origin_url: &str) -> Option<String> {
+    let rest = origin_url.strip_prefix("https://")?;
+    let host = rest.split('/').next()?;
+    let host = host.rsplit('@').next()?;
+    Some(format!("https://{host}/"))
+}
+
+/// `url` without the user name and password it may contain.
+pub fn redact(url: &str) -> String {
+    scrub(url, &GitSettings::default())
+}
+
+/// `text` with the secrets of `credentials` and the user names and passwords
+/// of urls replaced, for logging what git had to say.
+pub fn scrub(text: &str, credentials: &GitSettings) -> String {
+    let mut text = text.to_string();
+    let secrets = [credentials.token.clone(), authorization(credentials)];
+    for secret in secrets.into_iter().flatten() {
+        // The header is matched on its own too, it holds the token in base64.
+        let secret = secret.trim_start_matches("Basic ");
+        if !secret.is_empty() {
+            text = text.replace(secret, REDACTED);
+        }
+    }
+
+    let mut scrubbed = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    while let Some(i) = rest.find("://") {
+        let (before, after) = rest.split_at(i + 3);
+        scrubbed.push_str(before);
+
+        let authority_end = after
+            .find(|c: char| c == '/' || c.is_whitespace() || c == '\'' || c == '"')
+            .unwrap_or(after.len());
+        match after[..authority_end].rfind('@') {
+            Some(at) => {
+                scrubbed.push_str(REDACTED);
+                rest = &after[at..];
+            }
+            None => rest = after,
+        }
+    }
+    scrubbed.push_str(rest);
+    scrubbed
+}
+
+/// The last line git wrote to stderr, which usually says what went wrong.
+pub fn last_line(stderr: &str) -> &str {
+    stderr
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or("no error message")
+}
@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    config::{Backend, Settings},
+    git,
+    project::Projects,
+    util,
+};
+
+/// How long to wait for a remote repo to answer.
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the backend and the repos get checked, see [watch](`Health::watch`).
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The outcome of one of the checks done by [readiness](`Health::readiness`).
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    /// The project checked, not set for checks concerning the whole server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, project: Option<&str>, result: Result<String, String>) -> Self {
+        let ok = result.is_ok();
+        Check {
+            name,
+            project: project.map(str::to_string),
+            ok,
+            detail: result.unwrap_or_else(|e| e),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    /// Whether all checks passed.
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+/// The outcome of the checks which start processes or go over the network, see [refresh](`Health::refresh`).
+#[derive(Default)]
+struct Checked {
+    backend: Option<Result<String, String>>,
+    /// By the name of the project.
+    sources: HashMap<String, Result<String, String>>,
+}
+
+/// Checks whether the server is able to build and serve, going by the settings it got started with.
+pub struct Health {
+    backend: Backend,
+    /// The directory all projects compile in.
+    compilation_directory: PathBuf,
+    /// In bytes, 0 to not check.
+    min_free_space: u64,
+    checked: Mutex<Checked>,
+}
+
+impl Health {
+    pub fn new(settings: &Settings, compilation_directory: PathBuf) -> Self {
+        Health {
+            backend: settings.backend,
+            compilation_directory,
+            min_free_space: settings.limits.min_free_space * 1024 * 1024,
+            checked: Mutex::new(Checked::default()),
+        }
+    }
+
+    /// Checks that the build backend is installed and that every project can reach its repo,
+    /// for [readiness](`Health::readiness`) to report.
+    pub async fn refresh(&self, projects: &Projects) {
+        let backend = self.backend.command();
+        let found = if util::backend_not_found(self.backend) {
+            Err(format!("the \"{backend}\" executable could not be found"))
+        } else {
+            Ok(format!("{backend} found"))
+        };
+
+        let mut sources = HashMap::new();
+        for project in projects.values() {
+            let reachable =
+                util::source_reachable(&project.origin_url, &project.git(), SOURCE_TIMEOUT).await;
+            let source =
+                reachable.map(|_| format!("{} is reachable", git::redact(&project.origin_url)));
+            sources.insert(project.name.clone(), source);
+        }
+
+        *self.checked.lock().unwrap() = Checked {
+            backend: Some(found),
+            sources,
+        };
+    }
+
+    /// Does a [refresh](`Health::refresh`) every `interval`, the first one right away, so that
+    /// probes dont start processes or contact the repos themselves.
+    pub async fn watch(self: Arc<Self>, projects: Projects, interval: Duration) {
+        info!(
+            "Checking the backend and repos every {} seconds",
+            interval.as_secs()
+        );
+        loop {
+            self.refresh(&projects).await;
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Whether the build backend is installed and every project can reach its repo, going by
+    /// the last [refresh](`Health::refresh`), and whether there is enough free disk space and
+    /// every project can write to its compilation directory.
+    ///
+    /// A repo which can not be reached does not make a project unready while it still
+    /// has compiled targets in cache to serve.
+    pub async fn readiness(&self, projects: &Projects) -> Readiness {
+        const NOT_CHECKED: &str = "not checked yet";
+
+        let (backend, mut sources) = {
+            let checked = self.checked.lock().unwrap();
+            (checked.backend.clone(), checked.sources.clone())
+        };
+        let mut checks = vec![
+            Check::new(
+                "backend",
+                None,
+                backend.unwrap_or_else(|| Err(NOT_CHECKED.to_string())),
+            ),
+            Check::new("disk", None, self.check_disk()),
+        ];
+
+        for project in projects.values() {
+            let name = Some(project.name.as_str());
+            let directory = &project.compilation_directory;
+            checks.push(Check::new(
+                "compilation_directory",
+                name,
+                util::check_writable(directory).map(|_| format!("{directory:?} is writable")),
+            ));
+
+            let reachable = sources
+                .remove(&project.name)
+                .unwrap_or_else(|| Err(NOT_CHECKED.to_string()));
+            let source = match reachable {
+                Ok(detail) => Ok(detail),
+                Err(e) => match project.cache.lock().await.stats().entries {
+                    0 => Err(e),
+                    cached => Ok(format!(
+                        "{e}, still serving the {cached} artifacts in cache"
+                    )),
+                },
+            };
+            checks.push(Check::new("source", name, source));
+        }
+
+        Readiness {
+            ready: checks.iter().all(|c| c.ok),
+            checks,
+        }
+    }
+
+    fn check_disk(&self) -> Result<String, String> {
+        const MB: u64 = 1024 * 1024;
+
+        let free = match util::free_space(&self.compilation_directory) {
+            Some(free) => free,
+            None => return Ok("free space unknown".to_string()),
+        };
+
+        if free < self.min_free_space {
+            Err(format!(
+                "{} MB free, at least {} MB needed",
+                free / MB,
+                self.min_free_space / MB
+            ))
+        } else {
+            Ok(format!("{} MB free", free / MB))
+        }
+    }
+}
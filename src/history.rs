@@ -0,0 +1,323 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::is_target_triple,
+    package::file_name,
+    util::{self, BuildInfo},
+};
+
+/// The name of the file holding the [Revision](`Revision`) information in each revision directory.
+const REVISION_FILE: &str = "gload-revision.json";
+
+/// A previously compiled executable kept around in the [History](`History`).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Revision {
+    /// Identifies the revision, `<version>+<short commit>` or just the version when the
+    /// repo is not a git repo.
+    pub id: String,
+    pub info: BuildInfo,
+    /// The file name of the executable inside the revision directory.
+    pub executable: String,
+    pub created: SystemTime,
+}
+
+impl Revision {
+    fn new(info: BuildInfo, executable: String) -> Self {
+        let id = match &info.commit {
+            Some(commit) => format!("{}+{}", info.version, &commit[..commit.len().min(7)]),
+            None => info.version.clone(),
+        };
+
+        Revision {
+            id,
+            info,
+            executable,
+            created: SystemTime::now(),
+        }
+    }
+
+    /// Whether this revision is the one identified by `id`, either its full id or (at least
+    /// 7 characters of) the commit it was built from. See [find](`find`) for versions.
+    pub fn matches(&self, id: &str) -> bool {
+        let id = id.trim_start_matches('v');
+        self.id == id
+            || self
+                .info
+                .commit
+                .as_ref()
+                .is_some_and(|c| id.len() >= 7 && c.starts_with(id))
+    }
+}
+
+/// The revision identified by `id` out of `revisions`, see [matches](`Revision::matches`),
+/// or the only one with `id` as its version.
+///
+/// A version several revisions were built with identifies none of them, since there is no
+/// telling which of the commits a client is running.
+fn find(revisions: &[Revision], id: &str) -> Option<Revision> {
+    if let Some(revision) = revisions.iter().find(|r| r.matches(id)) {
+        return Some(revision.clone());
+    }
+
+    let version = id.trim_start_matches('v');
+    let mut same_version = revisions.iter().filter(|r| r.info.version == version);
+    let revision = same_version.next()?;
+    if same_version.next().is_some() {
+        warn!("Several revisions have the version {version}, {id} is ambiguous");
+        return None;
+    }
+    Some(revision.clone())
+}
+
+/// Which revisions the [History](`History`) keeps when a new one gets recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    /// Keep the newest `n` revisions.
+    Last(usize),
+    /// Keep every revision built from a tagged commit, along with the newest one.
+    Tagged,
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tagged" => Ok(Retention::Tagged),
+            n => match n.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(Retention::Last(n)),
+                _ => Err(format!(
+                    "Invalid retention: {s}, expected a number above 0 or \"tagged\""
+                )),
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Retention {
+    /// Accepts the same as [from_str](`Retention::from_str`), numbers can be given as is.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Count(usize),
+            Name(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Count(n) => n.to_string().parse(),
+            Raw::Name(s) => s.parse(),
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Retention {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Retention::Last(n) => serializer.serialize_u64(*n as u64),
+            Retention::Tagged => serializer.serialize_str("tagged"),
+        }
+    }
+}
+
+/// Keeps the executables of previous builds for each target so that older versions can still
+/// be downloaded and clients can be served binary patches instead of full downloads when updating.
+///
+/// Revisions are stored as `<directory>/<target_triple>/<revision id>/` holding the executable
+/// and the patches from older revisions to it as `<older revision id>.patch`.
+pub struct History {
+    directory: PathBuf,
+    retention: Retention,
+}
+
+impl History {
+    /// Creates a new [History](`History`) keeping revisions according to `retention`.
+    pub fn new(directory: PathBuf, retention: Retention) -> Self {
+        History {
+            directory,
+            retention,
+        }
+    }
+
+    /// Records a freshly compiled `executable` as the newest revision of `target_triple`
+    /// and creates a patch from the previous revision to it.
+    pub async fn record(
+        &self,
+        target_triple: &str,
+        info: BuildInfo,
+        executable: &Path,
+    ) -> Result<Revision, String> {
+        let revision = Revision::new(info, file_name(executable));
+        let previous = self.revisions(target_triple).into_iter().next();
+
+        if let Some(p) = &previous {
+            if p.id == revision.id {
+                debug!("{target_triple} revision {} is already recorded", p.id);
+                return Ok(p.clone());
+            }
+        }
+
+        let directory = self.revision_directory(target_triple, &revision.id);
+        let r = revision.clone();
+        let (d, e) = (directory.clone(), executable.to_path_buf());
+        tokio::task::spawn_blocking(move || -> io::Result<()> {
+            fs::create_dir_all(&d)?;
+            fs::copy(&e, d.join(&r.executable))?;
+            fs::write(d.join(REVISION_FILE), serde_json::to_vec(&r)?)
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("Failed to record revision {}: {e}", revision.id))?;
+
+        info!("Recorded revision {} of {target_triple}", revision.id);
+
+        if let Some(previous) = previous {
+            let old = self
+                .revision_directory(target_triple, &previous.id)
+                .join(&previous.executable);
+            let new = directory.join(&revision.executable);
+            let patch = directory.join(format!("{}.patch", previous.id));
+
+            let result = tokio::task::spawn_blocking(move || create_patch(&old, &new, &patch))
+                .await
+                .map_err(|e| e.to_string())?;
+            match result {
+                Ok(_) => info!("Created patch from {} to {}", previous.id, revision.id),
+                Err(e) => error!("Failed to create patch from {}: {e}", previous.id),
+            }
+        }
+
+        self.prune(target_triple);
+
+        Ok(revision)
+    }
+
+    /// Gets the recorded revisions of `target_triple`, newest first.
+    pub fn revisions(&self, target_triple: &str) -> Vec<Revision> {
+        let entries = match self.target_directory(target_triple).map(fs::read_dir) {
+            Some(Ok(e)) => e,
+            _ => return Vec::new(),
+        };
+
+        let mut revisions: Vec<Revision> = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| fs::read(e.path().join(REVISION_FILE)).ok())
+            .filter_map(|b| serde_json::from_slice(&b).ok())
+            .collect();
+
+        revisions.sort_by_key(|r| std::cmp::Reverse(r.created));
+        revisions
+    }
+
+    /// Gets the revision of `target_triple` identified by `id` along with the path to
+    /// its executable, see [find](`find`).
+    pub fn revision(&self, target_triple: &str, id: &str) -> Option<(Revision, PathBuf)> {
+        let revision = find(&self.revisions(target_triple), id)?;
+        let path = self
+            .revision_directory(target_triple, &revision.id)
+            .join(&revision.executable);
+
+        path.exists().then_some((revision, path))
+    }
+
+    /// Gets the targets which have recorded revisions.
+    pub fn targets(&self) -> Vec<String> {
+        let mut targets: Vec<String> = fs::read_dir(&self.directory)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter(|e| e.path().is_dir())
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        targets.sort();
+        targets
+    }
+
+    /// Gets the path of the patch from the revision identified by `from` to the newest revision,
+    /// see [find](`find`).
+    pub fn patch(&self, target_triple: &str, from: &str) -> Option<(Revision, PathBuf)> {
+        let revisions = self.revisions(target_triple);
+        let latest = revisions.first()?;
+        let from = find(&revisions, from).filter(|r| r.id != latest.id)?;
+
+        let path = self
+            .revision_directory(target_triple, &latest.id)
+            .join(format!("{}.patch", from.id));
+
+        path.exists().then_some((from, path))
+    }
+
+    /// Removes the revisions of every target which the [Retention](`Retention`) does not keep.
+    pub fn prune_all(&self) {
+        for target_triple in self.targets() {
+            self.prune(&target_triple);
+        }
+    }
+
+    /// Removes the revisions of `target_triple` which the [Retention](`Retention`) does not keep.
+    fn prune(&self, target_triple: &str) {
+        let revisions = self.revisions(target_triple);
+        let pruned = revisions
+            .iter()
+            .enumerate()
+            .filter(|(i, r)| match self.retention {
+                Retention::Last(n) => *i >= n,
+                Retention::Tagged => *i > 0 && r.info.tag.is_none(),
+            })
+            .map(|(_, r)| r);
+
+        for revision in pruned {
+            let directory = self.revision_directory(target_triple, &revision.id);
+            match fs::remove_dir_all(&directory) {
+                Ok(_) => info!("Pruned revision {} of {target_triple}", revision.id),
+                Err(e) => error!("Failed to prune {directory:?}: {e}"),
+            }
+        }
+    }
+
+    /// The directory the revisions of `target_triple` are kept in, `None` when it is not a
+    /// target triple, which keeps things like `../` out of the path.
+    fn target_directory(&self, target_triple: &str) -> Option<PathBuf> {
+        is_target_triple(target_triple).then(|| self.directory.join(target_triple))
+    }
+
+    fn revision_directory(&self, target_triple: &str, id: &str) -> PathBuf {
+        self.directory.join(target_triple).join(id)
+    }
+}
+
+/// Creates a zstd compressed bsdiff patch from `old` to `new` at `patch`.
+fn create_patch(old: &Path, new: &Path, patch: &Path) -> io::Result<()> {
+    let old = fs::read(old)?;
+    let new = fs::read(new)?;
+
+    let mut diff = Vec::new();
+    bsdiff::diff(&old, &new, &mut diff)?;
+
+    let temporary = util::temporary_path(patch);
+    fs::write(&temporary, zstd::encode_all(diff.as_slice(), 19)?)?;
+    fs::rename(&temporary, patch)
+}
+
+/// Applies a patch created by [History](`History`) to `old`, the way clients have to.
+pub fn apply_patch(old: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    let mut diff = Vec::new();
+    zstd::Decoder::new(patch)?.read_to_end(&mut diff)?;
+
+    let mut new = Vec::new();
+    bsdiff::patch(old, &mut diff.as_slice(), &mut new)?;
+    Ok(new)
+}
@@ -4,144 +4,534 @@
 mod tests;
 
 use axum::{
-    routing::{get, post},
+    handler::Handler,
+    middleware,
+    routing::{delete, get, post},
     Extension, Router,
 };
-use cache::Callback;
-use clap::{arg, command};
-use std::{fs::remove_dir_all, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
-use tokio::sync::Mutex;
-use tracing::{error, info, metadata::LevelFilter};
+use clap::{arg, command, ArgMatches, Command};
+use std::{collections::BTreeMap, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, Semaphore};
+use tracing::{error, info, warn};
 
+pub mod activity;
+pub mod admin;
+pub mod auth;
 pub mod cache;
+pub mod classify;
+pub mod config;
+pub mod encoding;
+pub mod git;
+pub mod health;
+pub mod history;
+pub mod manifest;
+pub mod metrics;
+pub mod package;
+pub mod project;
+pub mod ratelimit;
+pub mod reload;
 pub mod routes;
+pub mod shutdown;
+pub mod telemetry;
+pub mod tls;
 pub mod util;
 
-use crate::{cache::Cache, util::Config};
+use crate::{
+    activity::ActivityLog,
+    auth::{Auth, Role},
+    classify::Case,
+    config::Settings,
+    health::Health,
+    project::{Project, Projects},
+    ratelimit::RateLimiter,
+    reload::Reloader,
+    routes::PostData,
+    shutdown::Shutdown,
+    telemetry::Telemetry,
+};
 
 type TargetsCompiling = Arc<Mutex<Vec<String>>>;
 
+/// The routes of a single project, served from the root or nested under `/p/<name>`.
+fn project_routes() -> Router {
+    Router::new()
+        // Entry point for the application
+        .route("/", get(routes::get_index))
+        // Called by JS in index page
+        .route("/get_target", post(routes::get_target))
+        // Returns the actual compiled file
+        .route("/get_binary/:path", get(routes::send_binary))
+        // Returns the compiled file wrapped in a debian package
+        .route("/get_deb/:path", get(routes::send_deb))
+        // Returns the checksum of the compiled file
+        .route("/get_checksum/:path", get(routes::send_checksum))
+        // Returns a patch from a older revision to the latest one
+        .route("/get_patch/:path", get(routes::send_patch))
+        // Lists the revisions kept of every target
+        .route("/versions", get(routes::get_versions))
+        // Update manifest for self updating clients
+        .route("/api/latest", get(routes::get_latest))
+        // Scripts for installing from the command line
+        .route("/install.sh", get(routes::get_install_sh))
+        .route("/install.ps1", get(routes::get_install_ps1))
+}
+
 #[tokio::main]
 async fn main() {
     let matches = command!()
-        .arg(arg!(             <repo>    "The repo to compile and distribute"))
+        .arg(arg!(             [repo]    "The repo to compile and distribute"))
+        .arg(arg!(-c --config  [file]    "A TOML or YAML configuration file, see the README for the settings. (defaults to $GLOAD_CONFIG)"))
+        .arg(arg!(--projects   [file]    "A TOML file listing multiple repos to serve, each under /p/<name>, instead of <repo>."))
+        .arg(arg!(-r --ref     [ref]     "The branch, tag or commit of the repo to compile."))
         .arg(arg!(-t           [timeout] "How long values should live (in seconds) in the cache! Set to 0 for no cache timeout. (defaults to 1024 seconds)"))
         .arg(arg!(debug: -d --debug      "Toggled debug output"))
         .arg(arg!(--path    [path]    "The path to place \"repo_to_compile\" in. (defauls to \"./\""))
         .arg(arg!(-p --port    [port]    "The port number to host the server on (defaults to 3000"))
+        .arg(arg!(-b --bind    [address] "The address to listen on (defaults to 0.0.0.0)"))
+        .arg(arg!(--"tls-cert" [file]    "A PEM certificate chain to serve HTTPS with, together with --tls-key"))
+        .arg(arg!(--"tls-key"  [file]    "The PEM private key of --tls-cert"))
+        .arg(arg!(--"redirect-port" [port] "A port to redirect plain HTTP requests to HTTPS from"))
+        .arg(arg!(--backend    [backend] "What to compile with, \"cross\" or \"cargo\" (defaults to cross)"))
+        .arg(arg!(--"max-builds" [count] "How many targets may be compiled at the same time, 0 for no limit (defaults to 0)"))
+        .arg(arg!(--"drain-timeout" [seconds] "How long to wait for downloads and builds to finish when shutting down, 0 for no limit (defaults to 30)"))
         .arg(arg!(-n --name    [name]    "The name of the binary to return. Useful for when serving a repo which compiles multiple binaries."))
+        .arg(arg!(-k --keep    [retention] "How many revisions of each target to keep for downloading older versions and patching, or \"tagged\" to keep all tagged releases. (defaults to 2)"))
+        .arg(arg!(-i --include [file] ... "Extra files from the repo to put in tar.gz and zip archives, README and LICENSE files are always included."))
+        .arg(arg!(--"log-format" [format] "How to write logs, \"text\" or \"json\" (defaults to text)"))
+        .arg(arg!(--"otlp-endpoint" [url] "A OpenTelemetry collector to export spans to over OTLP/HTTP, such as http://localhost:4318"))
+        .arg(arg!(--"print-config"       "Print the resolved configuration as TOML and exit"))
+        .subcommand(
+            Command::new("classify")
+                .about("Guess the target triple of get_target payloads without starting the server")
+                .arg(arg!([payloads]              "A JSON or JSONL file of payloads, such as data.json or the activity log, - for stdin. Payloads with an \"expected\" triple are checked against it."))
+                .arg(arg!(--os           [os]      "The os of a single payload to classify, as the index page would send it (\"Linux\", \"Windows\" or \"Mac OS X\")"))
+                .arg(arg!(--"os-version" [version] "The os_version of the payload (defaults to \"-\")"))
+                .arg(arg!(--"user-agent" [agent]   "The user_agent of the payload")),
+        )
+        .subcommand(
+            Command::new("token")
+                .about("Make a new token for the tokens file of auth.tokens_file, printing its entry")
+                .arg(arg!(<name>              "The name of the token, which is the user name for basic auth"))
+                .arg(arg!(-r --role [role]    "What the token may do, \"download\", \"build\" or \"admin\" (defaults to download)")),
+        )
         .get_matches();
 
-    let log_level = if matches.contains_id("debug") {
-        LevelFilter::DEBUG
-    } else {
-        LevelFilter::INFO
-    };
+    match matches.subcommand() {
+        Some(("classify", matches)) => std::process::exit(classify(matches)),
+        Some(("token", matches)) => std::process::exit(token(matches)),
+        _ => {}
+    }
 
-    let sub = tracing_subscriber::FmtSubscriber::builder()
-        .with_level(true)
-        .with_target(false)
-        .with_max_level(log_level)
-        .finish();
+    let settings = match Settings::resolve(&matches) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(1);
+        }
+    };
 
-    tracing::subscriber::set_global_default(sub).unwrap();
+    let validation = settings.validate();
 
-    let port = matches.get_one::<String>("port")
-        .unwrap_or(&3000.to_string())
-        .parse::<u16>()
-        .expect("Invalid argument!");
+    if matches.contains_id("print-config") {
+        match settings.to_toml() {
+            Ok(toml) => print!("{toml}"),
+            Err(e) => eprintln!("Failed to print configuration: {e}"),
+        }
+        if let Err(e) = validation {
+            eprintln!("\nInvalid configuration:\n{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    if util::cross_not_found() {
-        error!("the \"cross\" executable could not be found, is it installed and in path?");
-        std::process::exit(0);
-    } else {
-        info!("Cross found!");
+    if let Err(e) = validation {
+        eprintln!("Invalid configuration:\n{e}");
+        std::process::exit(1);
     }
 
-    let mut compilation_directory: PathBuf = PathBuf::from(
-        matches
-            .get_one::<String>("path")
-            .unwrap_or(&"./".to_string())
-            .clone(),
-    );
-    if !compilation_directory.exists() {
-        error!("The location: {compilation_directory:?} does not exist!");
+    let telemetry = match telemetry::init(&settings) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Failed to set up logging: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let backend = settings.backend.command();
+    if util::backend_not_found(settings.backend) {
+        error!("the \"{backend}\" executable could not be found, is it installed and in path?");
         std::process::exit(0);
     } else {
-        info!("Found location {compilation_directory:?}");
+        info!("{backend} found!");
     }
+
+    let mut compilation_directory = settings.path.clone();
+    info!("Found location {compilation_directory:?}");
     compilation_directory.push("repo_to_compile");
 
-    let origin_url = matches.get_one::<String>("repo").unwrap().clone();
-    info!("Pointing at repo: {origin_url}");
+    // Ensure that compilation_directory exists and holds nothing but the projects served,
+    // each of them cleans up its own directory. A single repo is served from this one.
+    if settings.repo.is_none() {
+        let names: Vec<String> = settings
+            .served_projects()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        if let Err(e) = util::restore_compilation_directory(&compilation_directory, &names) {
+            error!(e);
+            return;
+        }
+    }
+
+    let builds = (settings.limits.max_builds > 0)
+        .then(|| Arc::new(Semaphore::new(settings.limits.max_builds)));
 
-    // Ensure that compilation_directory exists and is empty.
-    if let Err(e) = util::restore_compilation_directory(&compilation_directory) {
-        error!(e);
-        return;
+    let activity = if settings.activity.enabled {
+        let file = settings.activity.file(&settings.path);
+        let max_size = settings.activity.max_size * 1024 * 1024;
+        match ActivityLog::open(file, max_size, settings.activity.keep) {
+            Ok(log) => log,
+            Err(e) => {
+                error!(e);
+                return;
+            }
+        }
+    } else {
+        ActivityLog::disabled()
+    };
+    let activity = Arc::new(activity);
+
+    // Serve either the configured projects, each under /p/<name>,
+    // or the single repo given on the command line from the root.
+    let mut projects = BTreeMap::new();
+    for (name, project) in settings.served_projects() {
+        info!("Serving {name} from repo: {}", git::redact(&project.source));
+        let (base_path, directory) = if settings.repo.is_some() {
+            (String::new(), compilation_directory.clone())
+        } else {
+            (format!("/p/{name}"), compilation_directory.join(&name))
+        };
+
+        let project = Project::new(
+            name.clone(),
+            project,
+            base_path,
+            directory,
+            &settings,
+            builds.clone(),
+            activity.clone(),
+        )
+        .await;
+        match project {
+            Ok(p) => projects.insert(name, Arc::new(p)),
+            Err(e) => {
+                error!(e);
+                return;
+            }
+        };
     }
+    let projects: Projects = Arc::new(projects);
 
-    let time_out = matches
-        .get_one::<String>("timeout")
-        .unwrap_or(&1024.to_string())
-        .parse::<u64>()
-        .expect("Invalid argument!");
+    let auth = match Auth::new(&settings) {
+        Ok(a) => Arc::new(a),
+        Err(e) => {
+            error!(e);
+            return;
+        }
+    };
+    if let Some(file) = &settings.auth.tokens_file {
+        let downloads = if settings.auth.public_downloads {
+            "public"
+        } else {
+            "private"
+        };
+        info!("Authenticating with the tokens in {file:?}, downloads are {downloads}");
+    }
+    let limiter = Arc::new(RateLimiter::new(&settings));
 
-    if time_out == 0 {
-        info!("Cache timeout not set, data will not go out of cache.");
+    // Apply changes to the configuration files while running.
+    let reloader = Arc::new(Reloader::new(
+        matches,
+        settings.clone(),
+        projects.clone(),
+        auth.clone(),
+        limiter.clone(),
+    ));
+    tokio::spawn(reloader.clone().watch(Duration::new(2, 0)));
+    #[cfg(unix)]
+    tokio::spawn(reloader.on_hangup());
+
+    // Probing the backend and the repos is left to the background, /readyz reports the outcome.
+    let health = Arc::new(Health::new(&settings, compilation_directory.clone()));
+    tokio::spawn(
+        health
+            .clone()
+            .watch(projects.clone(), health::REFRESH_INTERVAL),
+    );
+
+    // build our application with some routes
+    let app = if settings.repo.is_none() {
+        let mut app = Router::new()
+            // Lists all projects
+            .route("/", get(routes::get_projects));
+        for project in projects.values() {
+            let extension = Extension(project.clone());
+            app = app
+                .nest(
+                    &project.base_path,
+                    project_routes()
+                        .layer(middleware::from_fn(metrics::count_served))
+                        .layer(extension.clone()),
+                )
+                // Nesting only serves the index without the trailing slash
+                .route(
+                    &format!("{}/", project.base_path),
+                    get(routes::get_index).layer(extension),
+                );
+        }
+        app
     } else {
-        info!("Cache timeout set to {time_out} seconds.");
+        let project = projects.values().next().unwrap().clone();
+        project_routes()
+            .layer(middleware::from_fn(metrics::count_served))
+            .layer(Extension(project))
+    };
+    let app = app
+        // Everything so far is for whoever may download, builds are checked by the routes starting them.
+        .route_layer(middleware::from_fn(|r, n| {
+            auth::require(Role::Download, r, n)
+        }))
+        // Probes for whatever is running us
+        .route("/healthz", get(routes::get_healthz))
+        .route("/readyz", get(routes::get_readyz))
+        .layer(Extension(health))
+        .layer(Extension(projects.clone()));
+
+    let admin = Router::new()
+        .route(
+            "/admin/cache",
+            get(routes::get_admin_cache).delete(routes::delete_admin_cache),
+        )
+        .route(
+            "/admin/cache/:project/:key",
+            delete(routes::delete_admin_cache_entry),
+        )
+        .route(
+            "/admin/rebuild/:project/:target_triple",
+            post(routes::post_admin_rebuild),
+        )
+        .route("/admin/builds", get(routes::get_admin_builds))
+        .route("/admin/downloads", get(routes::get_admin_downloads))
+        .route("/admin/logs", get(routes::get_admin_logs))
+        // Metrics about all projects for Prometheus
+        .route("/metrics", get(routes::get_metrics))
+        // Recent guesses, downloads and builds, which tell a lot about the clients
+        .route("/activity", get(routes::get_activity))
+        // Route layers, plain layers would apply to the fallback of the whole app too.
+        .route_layer(Extension(activity))
+        .route_layer(Extension(projects.clone()))
+        .route_layer(Extension(telemetry.logs.clone()))
+        .route_layer(middleware::from_fn(|r, n| auth::require(Role::Admin, r, n)));
+    info!("Serving the admin API under /admin along with /metrics and /activity");
+    if !auth.has_admin() {
+        warn!("Neither admin.token nor admin tokens are set, the admin API refuses every request");
     }
 
-    info!("Log level set to: {log_level}");
+    // The page itself holds nothing secret, it asks for a token to get the rest.
+    let app = if settings.admin.dashboard {
+        info!("Serving the admin dashboard at /admin");
+        app.route("/admin", get(routes::get_admin_dashboard))
+    } else {
+        app
+    };
+    let app = app
+        .merge(admin)
+        .layer(middleware::from_fn(auth::authenticate))
+        .layer(Extension(auth))
+        .layer(middleware::from_fn(ratelimit::identify))
+        .layer(Extension(limiter));
+    let app = app.layer(middleware::from_fn(telemetry::request_span));
 
-    // GODAHMN this is hacky
-    let thing = Box::new(compilation_directory.clone());
-    let dummy = Box::leak(thing.clone());
-    let callback: Option<Callback> = Some(Box::new(|x| {
-        let fname = dummy.join(x).into_os_string().into_string().unwrap();
-        if let Err(e) = remove_dir_all(&fname) {
-            info!("Callback failed to delete file: {fname} with error: {e:#?}");
-        } else {
-            info!("Erased \"{fname}\" from cache.");
+    // Stop accepting connections on SIGINT and SIGTERM, and give the ones still open
+    // the drain period to finish before aborting them.
+    let shutdown = Shutdown::listen(settings.limits.drain_timeout());
+
+    // run it
+    let addr = SocketAddr::from((settings.bind, settings.port));
+    let (cert, key) = match settings.tls.files() {
+        Some(files) => files,
+        None => {
+            info!("Listening on ip: {addr}");
+            let server = axum::Server::bind(&addr)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown.clone().requested());
+            tokio::select! {
+                result = server => result.unwrap(),
+                _ = shutdown.drained() => warn!("Drain period is over, aborting what is left"),
+            }
+            shut_down(&projects, telemetry).await;
+            return;
         }
-    }));
+    };
 
-    // Create cache
-    let cache = Arc::new(Mutex::new(
-        Cache::new(Duration::new(time_out, 0), callback).await,
+    let rustls = match tls::load(cert, key).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!(e);
+            return;
+        }
+    };
+    tokio::spawn(tls::watch(
+        rustls.clone(),
+        cert.to_path_buf(),
+        key.to_path_buf(),
+        Duration::new(10, 0),
     ));
 
-    // Create the protected vector to store the currently compiling targets in
-    // with capacity since we will NEVER store more than 99 targets at the same time.
-    let targets_compiling = Arc::new(Mutex::new(Vec::<String>::with_capacity(99)));
+    if settings.tls.redirect_port != 0 {
+        let redirect_addr = SocketAddr::from((settings.bind, settings.tls.redirect_port));
+        let redirect = Router::new()
+            .fallback(routes::redirect_to_https.into_service())
+            .layer(Extension(settings.port));
 
-    let config = Config::new(
-        matches.contains_id("debug"),
-        matches.get_one::<String>("name").cloned()
-    );
+        info!("Redirecting HTTP on {redirect_addr} to HTTPS");
+        tokio::spawn(async move {
+            if let Err(e) = axum::Server::bind(&redirect_addr)
+                .serve(redirect.into_make_service())
+                .await
+            {
+                error!("HTTP redirect listener failed: {e}");
+            }
+        });
+    }
 
-    println!("{config:?}");
+    let app = app.layer(middleware::from_fn(tls::https_headers));
 
-    // build our application with some routes
-    let app = Router::new()
-        // Entry point for the application
-        .route("/", get(routes::get_index))
-        // Called by JS in index page
-        .route("/get_target", post(routes::get_target))
-        // Returns the actual compiled file
-        .route("/get_binary/:path", get(routes::send_binary))
-        .layer(Extension(cache))
-        .layer(Extension(origin_url))
-        .layer(Extension(compilation_directory))
-        .layer(Extension(config))
-        .layer(Extension(targets_compiling));
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            let drain = shutdown.drain;
+            shutdown.requested().await;
+            handle.graceful_shutdown(drain);
+        }
+    });
 
-    // run it
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    info!("Listening on ip: {addr}");
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+    info!("Listening on ip: {addr} (HTTPS)");
+    axum_server::bind_rustls(addr, rustls)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
+    shut_down(&projects, telemetry).await;
+}
+
+/// Saves what is needed to pick up where we left off after restarting.
+async fn shut_down(projects: &Projects, telemetry: Telemetry) {
+    for project in projects.values() {
+        project.shut_down().await;
+    }
+    info!("Shut down");
+    telemetry.shut_down();
+}
+
+/// Runs the `token` subcommand, returning the exit code.
+///
+/// The entry goes to stdout for appending to the tokens file, the secret to stderr
+/// so that it does not end up in there.
+fn token(matches: &ArgMatches) -> i32 {
+    let name = matches.get_one::<String>("name").unwrap();
+    let role = match matches.get_one::<String>("role") {
+        Some(role) => match role.parse::<Role>() {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("{e}");
+                return 1;
+            }
+        },
+        None => Role::Download,
+    };
+
+    let entry = auth::new_secret()
+        .and_then(|secret| auth::token_entry(name, role, &secret).map(|entry| (secret, entry)));
+    match entry {
+        Ok((secret, entry)) => {
+            eprintln!("The secret of {name}, shown only this once: {secret}");
+            print!("{entry}");
+            0
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            1
+        }
+    }
+}
+
+/// Runs the `classify` subcommand, returning the exit code.
+fn classify(matches: &ArgMatches) -> i32 {
+    let cases = if let Some(file) = matches.get_one::<String>("payloads") {
+        match classify::load(Path::new(file)) {
+            Ok(cases) => cases,
+            Err(e) => {
+                eprintln!("{e}");
+                return 1;
+            }
+        }
+    } else if let Some(os) = matches.get_one::<String>("os") {
+        let arg = |name| matches.get_one::<String>(name).cloned();
+        vec![Case {
+            payload: PostData::new(
+                os.clone(),
+                arg("os-version").unwrap_or_else(|| "-".to_string()),
+                arg("user-agent").unwrap_or_default(),
+            ),
+            expected: None,
+        }]
+    } else {
+        eprintln!("Nothing to classify, pass a file of payloads or --os and --user-agent");
+        return 1;
+    };
+
+    // Payloads without expectations get their guesses printed, a single one on its own for scripts.
+    let unchecked: Vec<&Case> = cases.iter().filter(|c| c.expected.is_none()).collect();
+    if let [case] = unchecked.as_slice() {
+        match routes::guess_target_triple(&case.payload) {
+            Ok(target_triple) => println!("{target_triple}"),
+            Err(e) => {
+                eprintln!("{e}");
+                return 1;
+            }
+        }
+    } else {
+        for case in unchecked {
+            let guess = routes::guess_target_triple(&case.payload);
+            let payload = &case.payload;
+            println!(
+                "{}\t{}\t{}",
+                guess.as_deref().unwrap_or("-"),
+                payload.os,
+                payload.user_agent
+            );
+        }
+    }
+
+    let checked = cases.iter().filter(|c| c.expected.is_some()).count();
+    if checked == 0 {
+        return 0;
+    }
+
+    let mismatches = classify::replay(&cases);
+    for mismatch in &mismatches {
+        println!("{mismatch}");
+    }
+    eprintln!(
+        "{} of {checked} payloads guessed as expected",
+        checked - mismatches.len()
+    );
+
+    if mismatches.is_empty() {
+        0
+    } else {
+        1
+    }
 }
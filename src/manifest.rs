@@ -0,0 +1,70 @@
+use std::{fs, path::Path};
+
+use toml::{Table, Value};
+
+/// The parts of a repo's `Cargo.toml` that are needed for packaging and versioning.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub license: Option<String>,
+    pub authors: Vec<String>,
+    pub homepage: Option<String>,
+
+    /// The `[package.metadata]` table, used by the packaging backends for their own settings.
+    pub metadata: Table,
+}
+
+impl Manifest {
+    /// Reads and parses the `Cargo.toml` in the root of `repo_directory`.
+    pub fn read(repo_directory: &Path) -> Result<Self, String> {
+        let path = repo_directory.join("Cargo.toml");
+        let contents =
+            fs::read_to_string(&path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+
+        Manifest::parse(&contents)
+    }
+
+    /// Parses the contents of a `Cargo.toml`.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let table = contents
+            .parse::<Table>()
+            .map_err(|e| format!("Failed to parse Cargo.toml: {e}"))?;
+
+        let package = table
+            .get("package")
+            .and_then(Value::as_table)
+            .ok_or_else(|| "Cargo.toml has no [package] section".to_string())?;
+
+        let string = |key: &str| package.get(key).and_then(Value::as_str).map(str::to_string);
+
+        Ok(Manifest {
+            name: string("name").ok_or_else(|| "Cargo.toml has no package name".to_string())?,
+            version: string("version").unwrap_or_else(|| "0.0.0".to_string()),
+            description: string("description"),
+            license: string("license"),
+            authors: package
+                .get("authors")
+                .and_then(Value::as_array)
+                .map(|a| {
+                    a.iter()
+                        .filter_map(Value::as_str)
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            homepage: string("homepage").or_else(|| string("repository")),
+            metadata: package
+                .get("metadata")
+                .and_then(Value::as_table)
+                .cloned()
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Gets the `[package.metadata.<tool>]` table, if there is one.
+    pub fn tool_metadata(&self, tool: &str) -> Option<&Table> {
+        self.metadata.get(tool).and_then(Value::as_table)
+    }
+}
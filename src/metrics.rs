@@ -0,0 +1,303 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{middleware::Next, response::Response};
+use http::{Method, Request};
+
+use crate::{
+    project::{Project, Projects},
+    util,
+};
+
+/// The upper bounds of the build duration histogram buckets in seconds,
+/// builds take anywhere from seconds to most of a hour.
+const BUILD_BUCKETS: [f64; 11] = [
+    1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0,
+];
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    /// How many observations fell into each of the [BUILD_BUCKETS](`BUILD_BUCKETS`), not cumulative.
+    buckets: [u64; BUILD_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = BUILD_BUCKETS.iter().position(|b| value <= *b) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// The metrics of a single [Project](`Project`), the cache keeps its own [Stats](`crate::cache::Stats`).
+#[derive(Default)]
+pub struct Metrics {
+    /// Downloads by target triple and format.
+    requests: Mutex<BTreeMap<(String, String), u64>>,
+    /// Build durations by target triple and outcome.
+    builds: Mutex<BTreeMap<(String, &'static str), Histogram>>,
+    bytes_served: AtomicU64,
+    builds_waiting: AtomicI64,
+    builds_running: AtomicI64,
+}
+
+impl Metrics {
+    /// Counts a download of `target_triple` in `format`.
+    pub fn request(&self, target_triple: &str, format: &str) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((target_triple.to_string(), format.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Records a build of `target_triple` which took `duration`.
+    pub fn build_finished(&self, target_triple: &str, duration: Duration, success: bool) {
+        let outcome = if success { "success" } else { "failure" };
+        self.builds
+            .lock()
+            .unwrap()
+            .entry((target_triple.to_string(), outcome))
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /// How many times each target triple got downloaded in each format.
+    pub fn downloads(&self) -> BTreeMap<(String, String), u64> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn served(&self, bytes: u64) {
+        self.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a build waiting for a build slot, until the returned guard is dropped.
+    pub fn waiting(&self) -> Gauge<'_> {
+        Gauge::new(&self.builds_waiting)
+    }
+
+    /// Counts a build running, until the returned guard is dropped.
+    pub fn running(&self) -> Gauge<'_> {
+        Gauge::new(&self.builds_running)
+    }
+}
+
+/// Increments a gauge for as long as it lives, so that builds which get dropped halfway
+/// (when the client goes away for example) are not counted forever.
+pub struct Gauge<'a>(&'a AtomicI64);
+
+impl<'a> Gauge<'a> {
+    fn new(gauge: &'a AtomicI64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Gauge(gauge)
+    }
+}
+
+impl Drop for Gauge<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A metric with all of its samples, in the order they got added.
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: Vec<String>,
+}
+
+/// Builds up the Prometheus text format, keeping the samples of each metric together.
+#[derive(Default)]
+pub struct Exposition {
+    families: Vec<Family>,
+}
+
+impl Exposition {
+    /// Adds a sample of the metric `name`, `suffix` is appended to the name for the
+    /// parts of a histogram (`_bucket`, `_sum` and `_count`).
+    pub fn sample(
+        &mut self,
+        (name, kind, help): (&'static str, &'static str, &'static str),
+        suffix: &str,
+        labels: &[(&str, &str)],
+        value: impl Display,
+    ) {
+        let family = match self.families.iter().position(|f| f.name == name) {
+            Some(i) => &mut self.families[i],
+            None => {
+                self.families.push(Family {
+                    name,
+                    kind,
+                    help,
+                    samples: Vec::new(),
+                });
+                self.families.last_mut().unwrap()
+            }
+        };
+
+        let mut sample = format!("{name}{suffix}");
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+                .collect();
+            let _ = write!(sample, "{{{}}}", labels.join(","));
+        }
+        let _ = write!(sample, " {value}");
+        family.samples.push(sample);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for family in &self.families {
+            let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind);
+            for sample in &family.samples {
+                let _ = writeln!(out, "{sample}");
+            }
+        }
+        out
+    }
+}
+
+/// Escapes a label value, backslashes, quotes and newlines have to be.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+const REQUESTS: (&str, &str, &str) = (
+    "gload_requests_total",
+    "counter",
+    "Downloads by target triple and format.",
+);
+const BYTES_SERVED: (&str, &str, &str) = (
+    "gload_served_bytes_total",
+    "counter",
+    "Bytes sent in response bodies.",
+);
+const CACHE_HITS: (&str, &str, &str) = (
+    "gload_cache_hits_total",
+    "counter",
+    "Cache lookups which found what they were looking for.",
+);
+const CACHE_MISSES: (&str, &str, &str) = (
+    "gload_cache_misses_total",
+    "counter",
+    "Cache lookups which did not.",
+);
+const CACHE_EVICTIONS: (&str, &str, &str) = (
+    "gload_cache_evictions_total",
+    "counter",
+    "Compiled targets and packages which went out of cache.",
+);
+const CACHE_ENTRIES: (&str, &str, &str) = (
+    "gload_cache_entries",
+    "gauge",
+    "Compiled targets and packages in cache.",
+);
+const BUILD_DURATION: (&str, &str, &str) = (
+    "gload_build_duration_seconds",
+    "histogram",
+    "How long builds took by target triple and outcome, without waiting for a build slot.",
+);
+const BUILDS_WAITING: (&str, &str, &str) = (
+    "gload_builds_waiting",
+    "gauge",
+    "Builds waiting for a build slot.",
+);
+const BUILDS_RUNNING: (&str, &str, &str) = ("gload_builds_running", "gauge", "Builds in progress.");
+const DISK_USAGE: (&str, &str, &str) = (
+    "gload_disk_usage_bytes",
+    "gauge",
+    "Size of the compilation directory.",
+);
+
+/// Renders the metrics of all `projects` in the Prometheus text format.
+pub async fn render(projects: &Projects) -> String {
+    let mut exposition = Exposition::default();
+
+    for project in projects.values() {
+        let name = project.name.as_str();
+        let metrics = &project.metrics;
+
+        for ((target, format), count) in metrics.requests.lock().unwrap().iter() {
+            let labels = [("project", name), ("target", target), ("format", format)];
+            exposition.sample(REQUESTS, "", &labels, count);
+        }
+        let served = metrics.bytes_served.load(Ordering::Relaxed);
+        exposition.sample(BYTES_SERVED, "", &[("project", name)], served);
+
+        let stats = project.cache.lock().await.stats();
+        exposition.sample(CACHE_HITS, "", &[("project", name)], stats.hits);
+        exposition.sample(CACHE_MISSES, "", &[("project", name)], stats.misses);
+        exposition.sample(CACHE_EVICTIONS, "", &[("project", name)], stats.evictions);
+        exposition.sample(CACHE_ENTRIES, "", &[("project", name)], stats.entries);
+
+        for ((target, outcome), histogram) in metrics.builds.lock().unwrap().iter() {
+            let labels = [("project", name), ("target", target), ("outcome", outcome)];
+            let mut cumulative = 0;
+            for (bound, count) in BUILD_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let le = bound.to_string();
+                let labels = [labels.as_slice(), &[("le", le.as_str())]].concat();
+                exposition.sample(BUILD_DURATION, "_bucket", &labels, cumulative);
+            }
+            let labels_inf = [labels.as_slice(), &[("le", "+Inf")]].concat();
+            exposition.sample(BUILD_DURATION, "_bucket", &labels_inf, histogram.count);
+            exposition.sample(BUILD_DURATION, "_sum", &labels, histogram.sum);
+            exposition.sample(BUILD_DURATION, "_count", &labels, histogram.count);
+        }
+
+        let waiting = metrics.builds_waiting.load(Ordering::Relaxed);
+        exposition.sample(BUILDS_WAITING, "", &[("project", name)], waiting);
+        let running = metrics.builds_running.load(Ordering::Relaxed);
+        exposition.sample(BUILDS_RUNNING, "", &[("project", name)], running);
+
+        // Walking the build trees takes a while, keep it off the runtime.
+        let directory = project.compilation_directory.clone();
+        let usage = tokio::task::spawn_blocking(move || disk_usage(&directory))
+            .await
+            .unwrap_or(0);
+        exposition.sample(DISK_USAGE, "", &[("project", name)], usage);
+    }
+
+    exposition.render()
+}
+
+/// The size of everything in `directory`, 0 when it can not be read.
+fn disk_usage(directory: &Path) -> u64 {
+    fs_extra::dir::get_size(directory).unwrap_or(0)
+}
+
+/// Counts the bytes sent in the response bodies of a project's routes. Responses to HEAD requests do not have a body.
+pub async fn count_served<B>(request: Request<B>, next: Next<B>) -> Response {
+    let project = request.extensions().get::<Arc<Project>>().cloned();
+    let head = request.method() == Method::HEAD;
+
+    let response = next.run(request).await;
+
+    if let (Some(project), false) = (project, head) {
+        if let Some(length) = util::body_length(&response) {
+            project.metrics.served(length);
+        }
+    }
+
+    response
+}
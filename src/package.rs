@@ -0,0 +1,263 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use tracing::{debug, error};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    manifest::Manifest,
+    util::{self, Config},
+};
+
+mod deb;
+mod rpm;
+
+/// The formats a compiled executable can be delivered in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The naked executable, as produced by `cross`.
+    Binary,
+    /// A gzipped tarball, keeps the executable bit intact for unix users.
+    TarGz,
+    /// A zip archive, the format windows users expect.
+    Zip,
+    /// A Debian package, only available for linux targets.
+    Deb,
+    /// A RPM package, only available for linux targets.
+    Rpm,
+}
+
+impl Format {
+    /// Parses the `?format=` query parameter.
+    ///
+    /// `archive` picks whichever archive format suits the `target_triple` best,
+    /// no format at all means the naked executable.
+    pub fn from_query(format: Option<&str>, target_triple: &str) -> Result<Self, String> {
+        match format {
+            None | Some("binary") | Some("bin") => Ok(Format::Binary),
+            Some("tar.gz") | Some("tgz") => Ok(Format::TarGz),
+            Some("zip") => Ok(Format::Zip),
+            Some("deb") if deb::supports(target_triple) => Ok(Format::Deb),
+            Some("deb") => Err(format!(
+                "Can not build a debian package for {target_triple}"
+            )),
+            Some("rpm") if rpm::supports(target_triple) => Ok(Format::Rpm),
+            Some("rpm") => Err(format!("Can not build a rpm package for {target_triple}")),
+            Some("archive") => {
+                if target_triple.contains("windows") {
+                    Ok(Format::Zip)
+                } else {
+                    Ok(Format::TarGz)
+                }
+            }
+            Some(f) => Err(format!("Unknown format: {f}")),
+        }
+    }
+
+    /// The name of this format as accepted by [from_query](`Format::from_query`).
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Binary => "binary",
+            f => f.extension(),
+        }
+    }
+
+    /// The file extension for archives of this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Binary => "",
+            Format::TarGz => "tar.gz",
+            Format::Zip => "zip",
+            Format::Deb => "deb",
+            Format::Rpm => "rpm",
+        }
+    }
+
+    /// The `Content-Type` to send files of this format with.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Binary => "application/octet-stream",
+            Format::TarGz => "application/gzip",
+            Format::Zip => "application/zip",
+            Format::Deb => "application/vnd.debian.binary-package",
+            Format::Rpm => "application/x-rpm",
+        }
+    }
+
+    /// The key used for storing the packaged file in the [Cache](`crate::cache::Cache`).
+    /// Doubles as the file name inside `compilation_directory`.
+    pub fn cache_key(&self, target_triple: &str) -> String {
+        match self {
+            Format::Binary => target_triple.to_string(),
+            _ => format!("{target_triple}.{}", self.extension()),
+        }
+    }
+}
+
+/// Packages the executable at `executable_path` into a archive of the given `format`.
+///
+/// The archive gets placed at `compilation_directory/<cache_key>` and contains the executable,
+/// any README or LICENSE files found in the root of the repo and the extra files from the
+/// [Config](`Config`). Archives put everything in a single directory while system packages
+/// install the executable to `/usr/bin` and the rest as documentation.
+pub async fn package(
+    format: Format,
+    executable_path: &Path,
+    target_triple: &str,
+    compilation_directory: &Path,
+    config: &Config,
+) -> Result<PathBuf, String> {
+    let repo_directory = compilation_directory.join(target_triple);
+    let destination = compilation_directory.join(format.cache_key(target_triple));
+
+    let mut files = find_docs(&repo_directory);
+    for extra in config.package_files() {
+        let path = repo_directory.join(extra);
+        if path.is_file() {
+            files.push(path);
+        } else {
+            error!("Could not find extra file {path:?} to package, skipping it");
+        }
+    }
+
+    let executable_path = executable_path.to_path_buf();
+    let stem = executable_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("executable")
+        .to_string();
+    let root = format!("{stem}-{target_triple}");
+    let triple = target_triple.to_string();
+    let manifest = if matches!(format, Format::Deb | Format::Rpm) {
+        Manifest::read(&repo_directory)?
+    } else {
+        Manifest::default()
+    };
+
+    debug!("Packaging {executable_path:?} and {files:?} into {destination:?}");
+
+    let temporary = util::temporary_path(&destination);
+    let t = temporary.clone();
+    let result = tokio::task::spawn_blocking(move || match format {
+        Format::TarGz => write_tar_gz(&t, &root, &executable_path, &files),
+        Format::Zip => write_zip(&t, &root, &executable_path, &files),
+        Format::Deb => deb::write(&t, &manifest, &triple, &executable_path, &files),
+        Format::Rpm => rpm::write(&t, &manifest, &triple, &executable_path, &files),
+        Format::Binary => Ok(()),
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if let Err(e) = result.and_then(|_| fs::rename(&temporary, &destination)) {
+        error!("Failed to package {target_triple}: {e}");
+        let _ = fs::remove_file(&temporary);
+        return Err(format!("Failed to package {target_triple}!"));
+    }
+
+    Ok(destination)
+}
+
+/// Finds the README and LICENSE files in the root of `repo_directory`.
+fn find_docs(repo_directory: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(repo_directory) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut docs: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .filter(|p| {
+            let name = p
+                .file_name()
+                .map(|n| n.to_string_lossy().to_uppercase())
+                .unwrap_or_default();
+            ["README", "LICENSE", "LICENCE", "COPYING"]
+                .iter()
+                .any(|d| name.starts_with(d))
+        })
+        .collect();
+
+    docs.sort();
+    docs
+}
+
+pub(crate) fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn write_tar_gz(
+    destination: &Path,
+    root: &str,
+    executable: &Path,
+    files: &[PathBuf],
+) -> io::Result<()> {
+    let encoder = GzEncoder::new(File::create(destination)?, Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(fs::metadata(executable)?.len());
+    header.set_mode(0o755);
+    header.set_mtime(now());
+    builder.append_data(
+        &mut header,
+        format!("{root}/{}", file_name(executable)),
+        File::open(executable)?,
+    )?;
+
+    for file in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(fs::metadata(file)?.len());
+        header.set_mode(0o644);
+        header.set_mtime(now());
+        builder.append_data(
+            &mut header,
+            format!("{root}/{}", file_name(file)),
+            File::open(file)?,
+        )?;
+    }
+
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+fn write_zip(
+    destination: &Path,
+    root: &str,
+    executable: &Path,
+    files: &[PathBuf],
+) -> io::Result<()> {
+    let mut zip = ZipWriter::new(File::create(destination)?);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    zip.start_file(
+        format!("{root}/{}", file_name(executable)),
+        options.unix_permissions(0o755),
+    )?;
+    io::copy(&mut File::open(executable)?, &mut zip)?;
+
+    for file in files {
+        zip.start_file(
+            format!("{root}/{}", file_name(file)),
+            options.unix_permissions(0o644),
+        )?;
+        io::copy(&mut File::open(file)?, &mut zip)?;
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+pub(crate) fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
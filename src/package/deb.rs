@@ -0,0 +1,258 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use toml::{Table, Value};
+
+use super::{file_name, now};
+use crate::manifest::Manifest;
+
+/// Whether a debian package can be built for `target_triple`.
+pub fn supports(target_triple: &str) -> bool {
+    target_triple.contains("-linux-") && architecture(target_triple).is_some()
+}
+
+/// Maps the architecture of a target triple to its Debian name.
+pub fn architecture(target_triple: &str) -> Option<&'static str> {
+    let arch = target_triple.split('-').next()?;
+    let deb_arch = match arch {
+        "x86_64" => "amd64",
+        "i586" | "i686" => "i386",
+        "aarch64" => "arm64",
+        "armv7" => "armhf",
+        "arm" if target_triple.ends_with("hf") => "armhf",
+        "arm" => "armel",
+        "powerpc64le" => "ppc64el",
+        "riscv64gc" => "riscv64",
+        "s390x" => "s390x",
+        _ => return None,
+    };
+
+    Some(deb_arch)
+}
+
+/// Writes a `.deb` for the `executable` to `destination`.
+///
+/// Settings are read from `[package.metadata.deb]` in the repo's Cargo.toml when present,
+/// the same table `cargo-deb` uses, otherwise they are derived from `[package]`.
+pub fn write(
+    destination: &Path,
+    manifest: &Manifest,
+    target_triple: &str,
+    executable: &Path,
+    docs: &[PathBuf],
+) -> io::Result<()> {
+    let arch = architecture(target_triple).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("No debian architecture for {target_triple}"),
+        )
+    })?;
+
+    let empty = Table::new();
+    let meta = manifest.tool_metadata("deb").unwrap_or(&empty);
+    let package = package_name(
+        meta.get("name")
+            .and_then(Value::as_str)
+            .unwrap_or(&manifest.name),
+    );
+    let doc_directory = format!("./usr/share/doc/{package}/");
+
+    let data = tar_gz(|builder| {
+        for directory in [
+            "./",
+            "./usr/",
+            "./usr/bin/",
+            "./usr/share/",
+            "./usr/share/doc/",
+        ] {
+            append_directory(builder, directory)?;
+        }
+        append_directory(builder, &doc_directory)?;
+
+        append_file(
+            builder,
+            &format!("./usr/bin/{}", file_name(executable)),
+            executable,
+            0o755,
+        )?;
+        for doc in docs {
+            append_file(
+                builder,
+                &format!("{doc_directory}{}", file_name(doc)),
+                doc,
+                0o644,
+            )?;
+        }
+
+        Ok(())
+    })?;
+
+    let installed_size = (fs::metadata(executable)?.len()
+        + docs
+            .iter()
+            .filter_map(|d| fs::metadata(d).ok())
+            .map(|m| m.len())
+            .sum::<u64>())
+    .div_ceil(1024);
+
+    let control = control_file(manifest, meta, &package, arch, installed_size);
+    let control = tar_gz(|builder| {
+        append_directory(builder, "./")?;
+        append_bytes(builder, "./control", control.as_bytes(), 0o644)
+    })?;
+
+    let mut file = File::create(destination)?;
+    file.write_all(b"!<arch>\n")?;
+    append_ar(&mut file, "debian-binary", b"2.0\n")?;
+    append_ar(&mut file, "control.tar.gz", &control)?;
+    append_ar(&mut file, "data.tar.gz", &data)?;
+
+    Ok(())
+}
+
+/// `name` the way dpkg wants package names, lowercase and without underscores.
+pub fn package_name(name: &str) -> String {
+    name.to_lowercase().replace('_', "-")
+}
+
+/// Creates the contents of the `control` file.
+fn control_file(
+    manifest: &Manifest,
+    meta: &Table,
+    package: &str,
+    arch: &str,
+    installed_size: u64,
+) -> String {
+    let get = |key: &str| meta.get(key).and_then(Value::as_str);
+
+    let revision = get("revision").unwrap_or("1");
+    let version = if revision.is_empty() {
+        manifest.version.clone()
+    } else {
+        format!("{}-{revision}", manifest.version)
+    };
+    let maintainer = get("maintainer")
+        .map(str::to_string)
+        .or_else(|| manifest.authors.first().cloned())
+        .unwrap_or_else(|| "Unknown <unknown@localhost>".to_string());
+    let description = manifest
+        .description
+        .clone()
+        .unwrap_or_else(|| format!("{package}, served by gload"));
+
+    let mut control = format!(
+        "Package: {package}\nVersion: {version}\nArchitecture: {arch}\nMaintainer: {maintainer}\nInstalled-Size: {installed_size}\n"
+    );
+
+    // cargo-deb accepts "$auto" for dependencies, which needs dpkg-shlibdeps to resolve.
+    // We cant do that so just drop it.
+    if let Some(depends) = get("depends") {
+        let depends: Vec<&str> = depends
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty() && *d != "$auto")
+            .collect();
+        if !depends.is_empty() {
+            control.push_str(&format!("Depends: {}\n", depends.join(", ")));
+        }
+    }
+
+    control.push_str(&format!("Section: {}\n", get("section").unwrap_or("utils")));
+    control.push_str(&format!(
+        "Priority: {}\n",
+        get("priority").unwrap_or("optional")
+    ));
+    if let Some(homepage) = &manifest.homepage {
+        control.push_str(&format!("Homepage: {homepage}\n"));
+    }
+
+    // Only the first line of the description fits on the Description line,
+    // the rest goes below it along with the extended description.
+    let description = description.trim();
+    let (synopsis, rest) = description.split_once('\n').unwrap_or((description, ""));
+    control.push_str(&format!("Description: {}\n", synopsis.trim()));
+    control.push_str(&continuation_lines(rest));
+    if let Some(extended) = get("extended-description") {
+        control.push_str(&continuation_lines(extended));
+    }
+
+    control
+}
+
+/// `text` as continuation lines of a field, indented with a space and with a `.`
+/// standing in for empty lines.
+fn continuation_lines(text: &str) -> String {
+    text.trim()
+        .lines()
+        .map(|line| match line.trim() {
+            "" => " .\n".to_string(),
+            line => format!(" {line}\n"),
+        })
+        .collect()
+}
+
+fn tar_gz<F>(f: F) -> io::Result<Vec<u8>>
+where
+    F: FnOnce(&mut tar::Builder<GzEncoder<Vec<u8>>>) -> io::Result<()>,
+{
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    f(&mut builder)?;
+    builder.into_inner()?.finish()
+}
+
+fn append_directory<W: Write>(builder: &mut tar::Builder<W>, path: &str) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_size(0);
+    header.set_mode(0o755);
+    header.set_mtime(now());
+    builder.append_data(&mut header, path, io::empty())
+}
+
+fn append_file<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    source: &Path,
+    mode: u32,
+) -> io::Result<()> {
+    append_bytes(builder, path, &fs::read(source)?, mode)
+}
+
+fn append_bytes<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    contents: &[u8],
+    mode: u32,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(mode);
+    header.set_mtime(now());
+    builder.append_data(&mut header, path, contents)
+}
+
+/// Appends a member to a `ar` archive, the container format of `.deb` files.
+fn append_ar<W: Write>(writer: &mut W, name: &str, contents: &[u8]) -> io::Result<()> {
+    let header = format!(
+        "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+        name,
+        now(),
+        0,
+        0,
+        "100644",
+        contents.len()
+    );
+    writer.write_all(header.as_bytes())?;
+    writer.write_all(contents)?;
+
+    // Members are aligned to even offsets
+    if contents.len() % 2 == 1 {
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
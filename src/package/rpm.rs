@@ -0,0 +1,117 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use ::rpm::{BuildConfig, CompressionType, Dependency, FileOptions, PackageBuilder};
+use toml::{Table, Value};
+
+use super::file_name;
+use crate::manifest::Manifest;
+
+/// Whether a rpm package can be built for `target_triple`.
+pub fn supports(target_triple: &str) -> bool {
+    target_triple.contains("-linux-") && architecture(target_triple).is_some()
+}
+
+/// Maps the architecture of a target triple to its RPM name.
+pub fn architecture(target_triple: &str) -> Option<&'static str> {
+    let arch = target_triple.split('-').next()?;
+    let rpm_arch = match arch {
+        "x86_64" => "x86_64",
+        "i586" => "i586",
+        "i686" => "i686",
+        "aarch64" => "aarch64",
+        "armv7" => "armv7hl",
+        "powerpc64le" => "ppc64le",
+        "riscv64gc" => "riscv64",
+        "s390x" => "s390x",
+        _ => return None,
+    };
+
+    Some(rpm_arch)
+}
+
+/// Writes a `.rpm` for the `executable` to `destination`.
+///
+/// Settings are read from `[package.metadata.generate-rpm]` in the repo's Cargo.toml when present,
+/// the same table `cargo-generate-rpm` uses, otherwise they are derived from `[package]`.
+pub fn write(
+    destination: &Path,
+    manifest: &Manifest,
+    target_triple: &str,
+    executable: &Path,
+    docs: &[PathBuf],
+) -> io::Result<()> {
+    let arch = architecture(target_triple).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("No rpm architecture for {target_triple}"),
+        )
+    })?;
+
+    let empty = Table::new();
+    let meta = manifest.tool_metadata("generate-rpm").unwrap_or(&empty);
+    let get = |key: &str| meta.get(key).and_then(Value::as_str);
+
+    let name = get("name").unwrap_or(&manifest.name);
+    // RPM does not allow dashes in versions, pre-releases use a tilde to sort before the release.
+    let version = manifest.version.replace('-', "~");
+    let license = manifest.license.as_deref().unwrap_or("Unknown");
+    let description = manifest
+        .description
+        .clone()
+        .unwrap_or_else(|| format!("{name}, served by gload"));
+    let summary = get("summary").unwrap_or_else(|| description.lines().next().unwrap_or(name));
+
+    let mut builder = PackageBuilder::new(name, &version, license, arch, summary);
+    builder
+        .using_config(BuildConfig::default().compression(CompressionType::Gzip))
+        .release(get("release").unwrap_or("1"))
+        .description(description.as_str());
+
+    if let Some(homepage) = &manifest.homepage {
+        builder.url(homepage.as_str());
+    }
+    if let Some(packager) = manifest.authors.first() {
+        builder.packager(packager.as_str());
+    }
+
+    // cargo-generate-rpm lists requirements as a table of name = version requirement.
+    if let Some(requires) = meta.get("requires").and_then(Value::as_table) {
+        for (dependency, requirement) in requires {
+            let requirement = requirement.as_str().unwrap_or("*").trim();
+            let dependency = match requirement.strip_prefix(">=") {
+                Some(version) => Dependency::greater_eq(dependency, version.trim()),
+                None => Dependency::any(dependency),
+            };
+            builder.requires(dependency);
+        }
+    }
+
+    builder
+        .with_file(
+            executable,
+            FileOptions::new(format!("/usr/bin/{}", file_name(executable))).permissions(0o755),
+        )
+        .map_err(to_io)?;
+    for doc in docs {
+        builder
+            .with_file(
+                doc,
+                FileOptions::new(format!("/usr/share/doc/{name}/{}", file_name(doc)))
+                    .permissions(0o644)
+                    .doc(),
+            )
+            .map_err(to_io)?;
+    }
+
+    builder
+        .build()
+        .and_then(|package| package.write_file(destination))
+        .map_err(to_io)
+}
+
+fn to_io(e: ::rpm::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
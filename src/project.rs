@@ -0,0 +1,386 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Semaphore};
+use tracing::{error, info, warn};
+
+use crate::{
+    activity::ActivityLog,
+    cache::{Cache, Callback},
+    config::{Backend, GitSettings, Settings},
+    history::{History, Retention},
+    metrics::Metrics,
+    util::{self, Config},
+    TargetsCompiling,
+};
+
+/// A build in progress, see [track_build](`Project::track_build`).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct InFlight {
+    pub build_id: String,
+    pub target_triple: String,
+    /// When the build got asked for, in RFC 3339 format.
+    pub requested: String,
+    /// Whether it got a build slot yet.
+    pub running: bool,
+}
+
+/// Keeps a [InFlight](`InFlight`) build listed for as long as it lives, so that builds
+/// which get dropped halfway do not stay listed.
+pub struct Tracked<'a> {
+    project: &'a Project,
+    build_id: String,
+}
+
+impl Tracked<'_> {
+    /// Marks the build as having got its build slot.
+    pub fn running(&self) {
+        let mut builds = self.project.in_flight.lock().unwrap();
+        if let Some(build) = builds.iter_mut().find(|b| b.build_id == self.build_id) {
+            build.running = true;
+        }
+    }
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        let mut builds = self.project.in_flight.lock().unwrap();
+        builds.retain(|b| b.build_id != self.build_id);
+    }
+}
+
+/// The settings of a single project in the projects file.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectSettings {
+    /// The repo to compile and distribute, a git url or a local path.
+    pub source: String,
+    /// The branch, tag or commit to check out before compiling.
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    /// The target triples which may be compiled, the global `targets` when not set.
+    pub targets: Option<Vec<String>>,
+    /// The name of the binary to return, see `--name`.
+    pub binary: Option<String>,
+    /// Extra files to put in archives, see `--include`.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// How long compiled targets stay in cache, `cache.timeout` when not set.
+    pub timeout: Option<u64>,
+    /// Which revisions to keep, `cache.keep` when not set.
+    pub keep: Option<Retention>,
+    /// Credentials for cloning the repo, the global `git` settings when not set.
+    pub git: Option<GitSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectsFile {
+    projects: BTreeMap<String, ProjectSettings>,
+}
+
+/// Parses a projects file, which holds a `[projects.<name>]` table for each project.
+pub fn parse_projects(contents: &str) -> Result<BTreeMap<String, ProjectSettings>, String> {
+    let file: ProjectsFile =
+        toml::from_str(contents).map_err(|e| format!("Failed to parse projects: {e}"))?;
+
+    if file.projects.is_empty() {
+        return Err("No projects configured".to_string());
+    }
+
+    // The names end up in urls and directory names.
+    for name in file.projects.keys() {
+        if !is_valid_name(name) {
+            return Err(format!(
+                "Invalid project name: {name:?}, only letters, digits, '-' and '_' are allowed"
+            ));
+        }
+    }
+
+    Ok(file.projects)
+}
+
+/// Reads and parses the projects file at `path`.
+pub fn read_projects(path: &Path) -> Result<BTreeMap<String, ProjectSettings>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+
+    parse_projects(&contents)
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Derives a project name from the repo it serves, `https://github.com/me/tool.git` becomes `tool`.
+pub fn name_from_source(source: &str) -> String {
+    let name: String = source
+        .trim_end_matches('/')
+        .rsplit(['/', '\\', ':'])
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(".git")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+
+    if name.is_empty() {
+        "default".to_string()
+    } else {
+        name
+    }
+}
+
+/// Where the cache of a project is saved when shutting down, inside its compilation directory.
+const CACHE_FILE: &str = ".cache.json";
+/// Where the history of a project is kept, inside its compilation directory.
+const HISTORY_DIRECTORY: &str = ".history";
+
+/// A repo served by gload along with everything needed for compiling and serving it.
+/// Each project has its own cache, compilation directory and history.
+pub struct Project {
+    pub name: String,
+    pub origin_url: String,
+    pub git_ref: Option<String>,
+    /// The target triples allowed to be compiled, `None` allows all of them.
+    /// Can be changed while running, like the `config`.
+    targets: RwLock<Option<Vec<String>>>,
+    /// The path the project's routes are nested under, empty when served from the root.
+    pub base_path: String,
+    pub compilation_directory: PathBuf,
+    config: RwLock<Config>,
+    /// Whether builds show their output and what they run with, these stay as
+    /// started with as changing them needs a restart.
+    debug: bool,
+    backend: Backend,
+    /// The credentials to clone with, can be changed while running like the `config`.
+    git: RwLock<GitSettings>,
+    pub cache: Arc<Mutex<Cache>>,
+    pub targets_compiling: TargetsCompiling,
+    /// The cache keys of the packages being made, like `targets_compiling` for executables.
+    pub packages_packaging: TargetsCompiling,
+    pub history: Arc<History>,
+    /// Limits how many builds run at the same time, shared by all projects.
+    pub builds: Option<Arc<Semaphore>>,
+    pub metrics: Metrics,
+    /// Where guesses, downloads and builds get recorded, shared by all projects.
+    pub activity: Arc<ActivityLog>,
+    /// The builds in progress, oldest first.
+    in_flight: std::sync::Mutex<Vec<InFlight>>,
+}
+
+impl Project {
+    /// Creates the project `name` compiling in `compilation_directory`, which gets created
+    /// if it does not exist. Whatever `settings` leaves unset is taken from the `global` [Settings](`Settings`).
+    ///
+    /// The cache saved by [shut_down](`Project::shut_down`) is picked up again,
+    /// anything else left in `compilation_directory` gets removed.
+    pub async fn new(
+        name: String,
+        settings: ProjectSettings,
+        base_path: String,
+        compilation_directory: PathBuf,
+        global: &Settings,
+        builds: Option<Arc<Semaphore>>,
+        activity: Arc<ActivityLog>,
+    ) -> Result<Self, String> {
+        let timeout = settings.timeout.unwrap_or(global.cache.timeout);
+        if timeout == 0 {
+            info!("{name}: Cache timeout not set, data will not go out of cache.");
+        } else {
+            info!("{name}: Cache timeout set to {timeout} seconds.");
+        }
+
+        let mut cache = Cache::new(
+            Duration::new(timeout, 0),
+            Some(removal_callback(compilation_directory.clone())),
+        )
+        .await;
+
+        // The history is kept no matter what happened to the cache, what is in it
+        // gets pruned by the retention below instead.
+        let saved = compilation_directory.join(CACHE_FILE);
+        let mut keep = vec![HISTORY_DIRECTORY.to_string()];
+        if saved.exists() {
+            match cache.restore(&saved) {
+                Ok(keys) if keys.is_empty() => {}
+                Ok(keys) => {
+                    info!("{name}: Restored {keys:?} into cache");
+                    keep.extend(keys);
+                }
+                Err(e) => warn!("{name}: Failed to restore the cache from {saved:?}: {e}"),
+            }
+        }
+        util::restore_compilation_directory(&compilation_directory, &keep)
+            .map_err(|e| format!("Failed to restore {compilation_directory:?}: {e}"))?;
+        let cache = Arc::new(Mutex::new(cache));
+
+        // Keep older revisions of every target around for downloading and making patches from.
+        let retention = settings.keep.unwrap_or(global.cache.keep);
+        info!("{name}: Keeping revisions: {retention:?}");
+        let history = Arc::new(History::new(
+            compilation_directory.join(HISTORY_DIRECTORY),
+            retention,
+        ));
+        // The retention might have changed since the revisions were recorded.
+        history.prune_all();
+
+        let config = Project::build_config(&settings, global, global.debug, global.backend);
+        Ok(Project {
+            name,
+            origin_url: settings.source,
+            git_ref: settings.git_ref,
+            targets: RwLock::new(settings.targets.clone().or_else(|| global.targets.clone())),
+            base_path,
+            compilation_directory,
+            config: RwLock::new(config),
+            debug: global.debug,
+            backend: global.backend,
+            git: RwLock::new(settings.git.unwrap_or_else(|| global.git.clone())),
+            cache,
+            // with capacity since we will NEVER store more than 99 targets at the same time.
+            targets_compiling: Arc::new(Mutex::new(Vec::with_capacity(99))),
+            packages_packaging: Arc::new(Mutex::new(Vec::new())),
+            history,
+            builds,
+            metrics: Metrics::default(),
+            activity,
+            in_flight: std::sync::Mutex::new(Vec::new()),
+        })
+    }
+
+    fn build_config(
+        settings: &ProjectSettings,
+        global: &Settings,
+        debug: bool,
+        backend: Backend,
+    ) -> Config {
+        Config::new(
+            debug,
+            settings.binary.clone(),
+            settings.include.clone(),
+            backend,
+            global.limits.build_timeout(),
+        )
+    }
+
+    /// Applies the settings which can be changed while running: the allowed targets,
+    /// the cache timeout, how targets get built and packaged and the credentials to clone with.
+    /// Everything else only changes by creating the project anew.
+    pub async fn apply(&self, settings: &ProjectSettings, global: &Settings) {
+        *self.targets.write().unwrap() =
+            settings.targets.clone().or_else(|| global.targets.clone());
+        *self.config.write().unwrap() =
+            Project::build_config(settings, global, self.debug, self.backend);
+        *self.git.write().unwrap() = settings.git.clone().unwrap_or_else(|| global.git.clone());
+
+        let timeout = Duration::new(settings.timeout.unwrap_or(global.cache.timeout), 0);
+        let cache = self.cache.lock().await;
+        if cache.timeout() != timeout {
+            info!(
+                "{}: Cache timeout changed to {} seconds",
+                self.name,
+                timeout.as_secs()
+            );
+            cache.set_timeout(timeout);
+        }
+    }
+
+    /// The current [Config](`Config`) for building and packaging.
+    pub fn config(&self) -> Config {
+        self.config.read().unwrap().clone()
+    }
+
+    /// The current credentials for cloning the repo.
+    pub fn git(&self) -> GitSettings {
+        self.git.read().unwrap().clone()
+    }
+
+    /// Whether `target_triple` may be compiled for this project.
+    pub fn allows(&self, target_triple: &str) -> bool {
+        self.targets
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|t| t.iter().any(|t| t == target_triple))
+            .unwrap_or(true)
+    }
+
+    /// Whether `target_triple` can be downloaded without building it, because it is
+    /// in cache or being built already.
+    pub async fn is_built(&self, target_triple: &str) -> bool {
+        self.cache.lock().await.contains(target_triple)
+            || self
+                .targets_compiling
+                .lock()
+                .await
+                .iter()
+                .any(|t| t == target_triple)
+    }
+
+    /// Lists the build `build_id` of `target_triple` as in progress until the returned guard is dropped.
+    pub fn track_build(&self, build_id: &str, target_triple: &str) -> Tracked<'_> {
+        self.in_flight.lock().unwrap().push(InFlight {
+            build_id: build_id.to_string(),
+            target_triple: target_triple.to_string(),
+            requested: util::rfc3339(SystemTime::now()),
+            running: false,
+        });
+
+        Tracked {
+            project: self,
+            build_id: build_id.to_string(),
+        }
+    }
+
+    /// The builds in progress, oldest first.
+    pub fn in_flight(&self) -> Vec<InFlight> {
+        self.in_flight.lock().unwrap().clone()
+    }
+
+    /// Saves the cache to disk for the next start to pick up again. Builds still in progress
+    /// at this point get aborted, whatever they left behind is cleaned up on the next start.
+    pub async fn shut_down(&self) {
+        let compiling = self.targets_compiling.lock().await;
+        if !compiling.is_empty() {
+            warn!("{}: Aborting builds of {}", self.name, compiling.join(", "));
+        }
+
+        let file = self.compilation_directory.join(CACHE_FILE);
+        match self.cache.lock().await.save(&file) {
+            Ok(()) => info!("{}: Saved cache to {file:?}", self.name),
+            Err(e) => error!("{}: Failed to save cache to {file:?}: {e}", self.name),
+        }
+    }
+
+    /// The url clients reach this project through, see [server_url](`util::server_url`).
+    pub fn url(&self, headers: &http::HeaderMap) -> String {
+        format!("{}{}", util::server_url(headers), self.base_path)
+    }
+}
+
+/// Creates the [Callback](`Callback`) removing artifacts from `compilation_directory`
+/// when they go out of cache.
+fn removal_callback(compilation_directory: PathBuf) -> Callback {
+    Box::new(move |x| {
+        let path = compilation_directory.join(x);
+        let fname = path.clone().into_os_string().into_string().unwrap();
+        if let Err(e) = util::remove_artifact(&path) {
+            info!("Callback failed to delete file: {fname} with error: {e:#?}");
+        } else {
+            info!("Erased \"{fname}\" from cache.");
+        }
+    })
+}
+
+/// All projects served by this instance, by name.
+pub type Projects = Arc<BTreeMap<String, Arc<Project>>>;
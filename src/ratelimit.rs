@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header, HeaderMap, Request, StatusCode};
+use tracing::warn;
+
+use crate::config::{RateLimitSettings, Settings};
+
+/// How many clients are kept track of before the ones which are back to their full
+/// allowance get forgotten.
+const MAX_CLIENTS: usize = 1024;
+
+/// What a request costs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Downloading something which is built already, which is cheap.
+    Download,
+    /// Downloading something which has to be built first.
+    Build,
+}
+
+/// A token bucket holding up to `limit` tokens, refilled evenly over `period`.
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: u32, now: Instant) -> Self {
+        Bucket {
+            tokens: f64::from(limit),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: u32, period: Duration, now: Instant) {
+        let rate = f64::from(limit) / period.as_secs_f64();
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(limit));
+        self.updated = now;
+    }
+
+    /// How long until there is a token to take, zero when there is one.
+    fn wait(&self, limit: u32, period: Duration) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        let rate = f64::from(limit) / period.as_secs_f64();
+        Duration::from_secs_f64((1.0 - self.tokens) / rate)
+    }
+}
+
+/// Buckets of every client for a limit of `limit` per `period`, 0 for no limit.
+struct Buckets {
+    limit: u32,
+    period: Duration,
+    clients: HashMap<IpAddr, Bucket>,
+}
+
+impl Buckets {
+    fn new(limit: u32, period: Duration) -> Self {
+        Buckets {
+            limit,
+            period,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// The bucket of `client` brought up to date, a full one for clients not seen before.
+    fn bucket(&mut self, client: IpAddr, now: Instant) -> &mut Bucket {
+        if self.clients.len() >= MAX_CLIENTS && !self.clients.contains_key(&client) {
+            let (limit, period) = (self.limit, self.period);
+            self.clients.retain(|_, b| {
+                b.refill(limit, period, now);
+                b.tokens < f64::from(limit)
+            });
+        }
+
+        let (limit, period) = (self.limit, self.period);
+        let bucket = self
+            .clients
+            .entry(client)
+            .or_insert_with(|| Bucket::full(limit, now));
+        bucket.refill(limit, period, now);
+        bucket
+    }
+}
+
+struct State {
+    downloads: Buckets,
+    builds: Buckets,
+    global_builds: Bucket,
+    global_limit: u32,
+}
+
+/// Limits how often clients may download and build, see `[rate_limit]` in the README.
+pub struct RateLimiter {
+    state: Mutex<State>,
+    trust_forwarded_for: RwLock<bool>,
+}
+
+const MINUTE: Duration = Duration::from_secs(60);
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+impl RateLimiter {
+    pub fn new(settings: &Settings) -> Self {
+        let limits = &settings.rate_limit;
+        RateLimiter {
+            state: Mutex::new(State {
+                downloads: Buckets::new(limits.downloads_per_minute, MINUTE),
+                builds: Buckets::new(limits.builds_per_hour, HOUR),
+                global_builds: Bucket::full(limits.global_builds_per_hour, Instant::now()),
+                global_limit: limits.global_builds_per_hour,
+            }),
+            trust_forwarded_for: RwLock::new(limits.trust_forwarded_for),
+        }
+    }
+
+    /// Takes over changed limits, clients keep what they have left of their allowance
+    /// up to the new limit.
+    pub fn apply(&self, settings: &RateLimitSettings) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let now = Instant::now();
+        for (buckets, limit) in [
+            (&mut state.downloads, settings.downloads_per_minute),
+            (&mut state.builds, settings.builds_per_hour),
+        ] {
+            buckets.limit = limit;
+            for bucket in buckets.clients.values_mut() {
+                bucket.refill(limit, buckets.period, now);
+            }
+        }
+        state.global_limit = settings.global_builds_per_hour;
+        let limit = state.global_limit;
+        state.global_builds.refill(limit, HOUR, now);
+        *self.trust_forwarded_for.write().unwrap() = settings.trust_forwarded_for;
+    }
+
+    /// Takes what a request of `kind` from `client` costs out of its allowance, or returns
+    /// how long to wait until it may be made.
+    pub fn check(&self, client: IpAddr, kind: Kind) -> Result<(), Duration> {
+        self.check_at(client, kind, Instant::now())
+    }
+
+    /// [check](`RateLimiter::check`) as of `now`.
+    pub fn check_at(&self, client: IpAddr, kind: Kind, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            downloads,
+            builds,
+            global_builds,
+            global_limit,
+        } = &mut *state;
+
+        match kind {
+            Kind::Download => {
+                if downloads.limit == 0 {
+                    return Ok(());
+                }
+                let (limit, period) = (downloads.limit, downloads.period);
+                let bucket = downloads.bucket(client, now);
+                take(&mut [(bucket, limit, period)])
+            }
+            Kind::Build => {
+                let mut buckets = Vec::new();
+                if builds.limit > 0 {
+                    let (limit, period) = (builds.limit, builds.period);
+                    buckets.push((builds.bucket(client, now), limit, period));
+                }
+                if *global_limit > 0 {
+                    global_builds.refill(*global_limit, HOUR, now);
+                    buckets.push((global_builds, *global_limit, HOUR));
+                }
+                take(&mut buckets)
+            }
+        }
+    }
+
+    /// The address a request came from, the last one in `X-Forwarded-For` when
+    /// `rate_limit.trust_forwarded_for` is set and `peer` otherwise.
+    ///
+    /// The last address is the one the proxy in front of us added, everything before it
+    /// was sent by the client and can be made up to get around the limits.
+    pub fn client(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !*self.trust_forwarded_for.read().unwrap() {
+            return peer;
+        }
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .last()
+            .and_then(|ip| ip.trim().parse().ok())
+            .unwrap_or(peer)
+    }
+}
+
+/// Takes a token out of every bucket when all of them have one, so that being refused
+/// by one limit does not use up the others.
+fn take(buckets: &mut [(&mut Bucket, u32, Duration)]) -> Result<(), Duration> {
+    let wait = buckets
+        .iter()
+        .map(|(bucket, limit, period)| bucket.wait(*limit, *period))
+        .max()
+        .unwrap_or(Duration::ZERO);
+    if !wait.is_zero() {
+        return Err(wait);
+    }
+
+    for (bucket, ..) in buckets.iter_mut() {
+        bucket.tokens -= 1.0;
+    }
+    Ok(())
+}
+
+/// The client a request came from, put in the extensions of every request by
+/// [identify](`identify`).
+#[derive(Clone)]
+pub struct Client {
+    pub address: IpAddr,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Client {
+    /// Takes a request of `kind` out of the allowance of this client,
+    /// returning the response refusing it when it is over the limit.
+    pub fn limit(&self, kind: Kind) -> Option<Response> {
+        let wait = self.limiter.check(self.address, kind).err()?;
+        Some(too_many_requests(self.address, kind, wait))
+    }
+}
+
+/// Works out which [Client](`Client`) every request came from, with the
+/// [RateLimiter](`RateLimiter`) in a `Extension` layered on top.
+pub async fn identify<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let limiter = request.extensions().get::<Arc<RateLimiter>>().cloned();
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0.ip());
+
+    if let (Some(limiter), Some(peer)) = (limiter, peer) {
+        let client = Client {
+            address: limiter.client(peer, request.headers()),
+            limiter,
+        };
+        request.extensions_mut().insert(client);
+    }
+    next.run(request).await
+}
+
+/// The `429 Too Many Requests` response to a request refused for `wait`.
+pub fn too_many_requests(client: IpAddr, kind: Kind, wait: Duration) -> Response {
+    // Retry-After only takes whole seconds.
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let what = match kind {
+        Kind::Download => "downloads",
+        Kind::Build => "builds",
+    };
+    warn!("Rate limited {what} of {client}, retry after {seconds}s");
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, seconds.to_string())],
+        format!("Too many {what}, try again in {seconds} seconds"),
+    )
+        .into_response()
+}
@@ -0,0 +1,146 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use clap::ArgMatches;
+use tracing::{debug, error, info, warn};
+
+use crate::{auth::Auth, config::Settings, project::Projects, ratelimit::RateLimiter};
+
+/// Applies changes to the configuration while running, as far as they can be applied
+/// without restarting.
+pub struct Reloader {
+    matches: ArgMatches,
+    /// The settings the server got started with, changes to the settings which need
+    /// a restart are reported against these until it happens.
+    started: Settings,
+    /// The settings applied last.
+    applied: Mutex<Settings>,
+    projects: Projects,
+    auth: Arc<Auth>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Reloader {
+    pub fn new(
+        matches: ArgMatches,
+        settings: Settings,
+        projects: Projects,
+        auth: Arc<Auth>,
+        limiter: Arc<RateLimiter>,
+    ) -> Self {
+        Reloader {
+            matches,
+            started: settings.clone(),
+            applied: Mutex::new(settings),
+            projects,
+            auth,
+            limiter,
+        }
+    }
+
+    /// Resolves the settings again and applies whatever can be applied while running.
+    /// Invalid settings are rejected as a whole, leaving the current ones in place.
+    ///
+    /// Returns the settings which changed but need a restart.
+    pub async fn reload(&self) -> Result<Vec<String>, String> {
+        let new = Settings::resolve(&self.matches)?;
+        new.validate()?;
+        // The tokens file is read again every time, it may have changed on its own.
+        self.auth.apply(&new)?;
+
+        let changes = self.applied.lock().unwrap().live_changes(&new);
+        self.limiter.apply(&new.rate_limit);
+        for (name, settings) in new.served_projects() {
+            if let Some(project) = self.projects.get(&name) {
+                project.apply(&settings, &new).await;
+            }
+        }
+
+        if changes.is_empty() {
+            debug!("Reloaded configuration, nothing changed which can be applied live");
+        } else {
+            info!("Reloaded configuration, applied: {}", changes.join(", "));
+        }
+
+        let restart = self.started.restart_required(&new);
+        if !restart.is_empty() {
+            warn!(
+                "Changes to {} only take effect after a restart",
+                restart.join(", ")
+            );
+        }
+
+        *self.applied.lock().unwrap() = new;
+        Ok(restart)
+    }
+
+    /// The files the settings are read from, which get watched for changes.
+    fn files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = Settings::config_file(&self.matches).into_iter().collect();
+        if let Some(projects) = self.matches.get_one::<String>("projects") {
+            files.push(PathBuf::from(projects));
+        }
+        if let Some(tokens) = &self.applied.lock().unwrap().auth.tokens_file {
+            files.push(tokens.clone());
+        }
+        files
+    }
+
+    /// Reloads whenever one of the configuration files changes, checking every `interval`.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut files = self.files();
+        if files.is_empty() {
+            return;
+        }
+        info!("Watching {files:?} for changes");
+
+        let modified = |files: &[PathBuf]| -> Vec<Option<SystemTime>> {
+            files
+                .iter()
+                .map(|f| f.metadata().and_then(|m| m.modified()).ok())
+                .collect()
+        };
+
+        let mut last = modified(&files);
+        loop {
+            tokio::time::sleep(interval).await;
+
+            // The tokens file can be moved elsewhere by the configuration.
+            files = self.files();
+            let current = modified(&files);
+            if current == last {
+                continue;
+            }
+            last = current;
+
+            info!("Configuration changed, reloading");
+            if let Err(e) = self.reload().await {
+                error!("Failed to reload configuration, keeping the current one: {e}");
+            }
+        }
+    }
+
+    /// Reloads whenever the process receives SIGHUP.
+    #[cfg(unix)]
+    pub async fn on_hangup(self: Arc<Self>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {e}");
+                return;
+            }
+        };
+
+        while hangups.recv().await.is_some() {
+            info!("Received SIGHUP, reloading configuration");
+            if let Err(e) = self.reload().await {
+                error!("Failed to reload configuration, keeping the current one: {e}");
+            }
+        }
+    }
+}
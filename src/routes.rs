@@ -1,28 +1,82 @@
 use axum::{
     body::{self, Full},
-    extract::Path,
-    response::{IntoResponse, Response},
+    extract::{Host, Path, Query},
+    response::{Html, IntoResponse, Redirect, Response},
     Extension, Json,
 };
-use http::StatusCode;
+use http::{header, HeaderMap, Method, StatusCode, Uri};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     path::PathBuf,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
-use tokio::{fs::File, io::AsyncReadExt, sync::Mutex, time::sleep};
-use tracing::{debug, error, info};
+use tokio::{fs::File, io::AsyncReadExt, time::sleep};
+use tracing::{debug, error, field, info, instrument, Instrument, Span};
 
-use crate::{cache::Cache, util::Config};
 use crate::util;
-use crate::TargetsCompiling;
+use crate::{
+    activity::{ActivityLog, Event, Filter},
+    admin::{self, Build, CachedEntry, Downloads, Evicted},
+    auth::{self, Access, Role},
+    git,
+    health::Health,
+    metrics,
+    package::{self, Format},
+    project::{Project, Projects},
+    ratelimit::{Client, Kind},
+    telemetry::{self, LogBuffer},
+    tls,
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct PostData {
-    os: String,
-    os_version: String,
-    user_agent: String,
+    pub os: String,
+    pub os_version: String,
+    pub user_agent: String,
+}
+
+impl PostData {
+    pub fn new(os: String, os_version: String, user_agent: String) -> Self {
+        PostData {
+            os,
+            os_version,
+            user_agent,
+        }
+    }
+
+    /// Creates the [PostData](`PostData`) a browser on the given `os` and `arch` would have sent,
+    /// as given through the query parameters of the index page.
+    ///
+    /// Only the combinations in [COMMAND_LINE_TARGETS](`COMMAND_LINE_TARGETS`) are accepted,
+    /// guessing would get everything else a executable which does not run. See
+    /// [query_target_triple](`query_target_triple`) for the ones a project allows.
+    pub fn from_query(os: &str, arch: &str) -> Result<Self, String> {
+        let (os_name, os) = match os.to_lowercase().as_str() {
+            "linux" => ("linux", "Linux"),
+            "windows" | "win" => ("windows", "Windows"),
+            "macos" | "mac" | "darwin" | "osx" => ("macos", "Mac OS X"),
+            _ => return Err(format!("Unknown os: {os}")),
+        };
+
+        let arch = match arch.to_lowercase().as_str() {
+            "x86_64" | "amd64" | "x64" => "x86_64",
+            "aarch64" | "arm64" => "aarch64",
+            "i686" | "i386" | "x86" => "i686",
+            _ => return Err(format!("Unknown architecture: {arch}")),
+        };
+
+        if !COMMAND_LINE_TARGETS.contains(&(os_name, arch)) {
+            return Err(format!("There are no executables for {os_name} on {arch}"));
+        }
+
+        Ok(PostData::new(
+            os.to_string(),
+            "-".to_string(),
+            arch.to_string(),
+        ))
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -30,24 +84,228 @@ pub struct ResponseData {
     target_triple: String,
 }
 
-pub async fn get_index() -> impl IntoResponse {
+/// The query parameters command line clients can use on the index page
+/// to skip the detection and get sent straight to their executable.
+#[derive(Debug, Deserialize)]
+pub struct IndexQuery {
+    os: Option<String>,
+    arch: Option<String>,
+    format: Option<String>,
+}
+
+/// The `?os=` and `?arch=` combinations there are executables for, listed to command line clients.
+const COMMAND_LINE_TARGETS: [(&str, &str); 7] = [
+    ("linux", "x86_64"),
+    ("linux", "aarch64"),
+    ("linux", "i686"),
+    ("windows", "x86_64"),
+    ("windows", "i686"),
+    ("macos", "x86_64"),
+    ("macos", "aarch64"),
+];
+
+pub async fn get_index(
+    Extension(project): Extension<Arc<Project>>,
+    headers: HeaderMap,
+    Query(query): Query<IndexQuery>,
+) -> Result<Response, String> {
+    if let Some(os) = &query.os {
+        let arch = query.arch.as_deref().unwrap_or("x86_64");
+        let target_triple = match query_target_triple(&project, os, arch) {
+            Ok(target_triple) => target_triple,
+            Err(e) => {
+                info!("Refused {os} on {arch}: {e}");
+                return Ok((StatusCode::BAD_REQUEST, e).into_response());
+            }
+        };
+
+        let mut location = format!("{}/get_binary/{target_triple}", project.base_path);
+        if let Some(format) = &query.format {
+            Format::from_query(Some(format), &target_triple)?;
+            location.push_str(&format!("?format={format}"));
+        }
+
+        info!("Redirecting {os} on {arch} to {location}");
+        return Ok(Redirect::temporary(&location).into_response());
+    }
+
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+
+    if is_command_line_client(user_agent) {
+        debug!("Command line client detected: {user_agent:?}");
+        return Ok(command_line_usage(&project, &project.url(&headers)).into_response());
+    }
+
     // Send the html of the page which gets the target triple
     let mut file = File::open("templates/index.html").await.unwrap();
     let mut html = String::new();
     file.read_to_string(&mut html).await.unwrap();
 
-    Response::builder()
+    Ok(Response::builder()
         .status(StatusCode::OK)
         .body(body::boxed(Full::from(html)))
-        .unwrap()
+        .unwrap())
+}
+
+/// Whether the `user_agent` belongs to something like `curl` or `wget` rather than a browser.
+pub fn is_command_line_client(user_agent: &str) -> bool {
+    // Every browser claims to be Mozilla, PowerShell does so too though.
+    !user_agent.starts_with("Mozilla/") || user_agent.contains("PowerShell")
 }
 
-pub async fn get_target(Json(json): Json<PostData>) -> Result<impl IntoResponse, String> {
+/// The plain text explanation sent to command line clients instead of the index page.
+fn command_line_usage(project: &Project, server: &str) -> String {
+    let mut usage = format!(
+        "This server compiles and serves {} for your platform.\n\n\
+         Download one by picking your os and architecture:\n    \
+         curl -fLOJ \"{server}/?os=<os>&arch=<arch>\"\n\n\
+         Or install it directly:\n    \
+         curl -fsSL {server}/install.sh | sh\n    \
+         irm {server}/install.ps1 | iex\n\n\
+         Add &format=tar.gz, zip, deb or rpm to get a package instead of the executable.\n\n\
+         Targets:\n",
+        project.name
+    );
+
+    for (os, arch, target_triple) in command_line_targets(project) {
+        let query = format!("?os={os}&arch={arch}");
+        usage.push_str(&format!("    {query:<24}{target_triple}\n"));
+    }
+
+    usage
+}
+
+/// The `?os=` and `?arch=` combinations `project` has executables for, with their target triples.
+fn command_line_targets(project: &Project) -> Vec<(&'static str, &'static str, String)> {
+    COMMAND_LINE_TARGETS
+        .iter()
+        .filter_map(|&(os, arch)| {
+            let target_triple = PostData::from_query(os, arch)
+                .and_then(|j| guess_target_triple(&j))
+                .ok()?;
+            project
+                .allows(&target_triple)
+                .then_some((os, arch, target_triple))
+        })
+        .collect()
+}
+
+/// The target triple for `?os=` and `?arch=`, when `project` allows it. Otherwise the error
+/// lists what is available instead, as guessing would send clients a executable which does not run.
+pub fn query_target_triple(project: &Project, os: &str, arch: &str) -> Result<String, String> {
+    let target_triple = PostData::from_query(os, arch)
+        .and_then(|j| guess_target_triple(&j))
+        .and_then(|target_triple| {
+            if project.allows(&target_triple) {
+                Ok(target_triple)
+            } else {
+                Err(format!(
+                    "{} is not available for {target_triple}",
+                    project.name
+                ))
+            }
+        });
+
+    target_triple.map_err(|e| {
+        let available: Vec<String> = command_line_targets(project)
+            .iter()
+            .map(|(os, arch, _)| format!("{os}/{arch}"))
+            .collect();
+        format!("{e}, available are {}", available.join(", "))
+    })
+}
+
+/// The index of a instance serving multiple projects, links to the index page of each project.
+pub async fn get_projects(
+    Extension(projects): Extension<Projects>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+
+    if is_command_line_client(user_agent) {
+        let server = util::server_url(&headers);
+        let mut usage = "This server compiles and serves executables for the following projects, \
+                         see their pages for how to download them:\n"
+            .to_string();
+        for project in projects.values() {
+            usage.push_str(&format!(
+                "    {:<24}{server}{}/\n",
+                project.name, project.base_path
+            ));
+        }
+        return usage.into_response();
+    }
+
+    let items: String = projects
+        .values()
+        .map(|p| {
+            format!(
+                "      <li><a href=\"{}/\">{}</a></li>\n",
+                p.base_path, p.name
+            )
+        })
+        .collect();
+
+    Html(format!(
+        "<!DOCTYPE html>\n<html>\n  <head>\n    <title>Projects</title>\n  </head>\n\n  \
+         <body>\n    <ul>\n{items}    </ul>\n  </body>\n</html>\n"
+    ))
+    .into_response()
+}
+
+pub async fn get_target(
+    Extension(project): Extension<Arc<Project>>,
+    Json(json): Json<PostData>,
+) -> Result<impl IntoResponse, String> {
     debug!("Recieved {json:?} on get_target");
+
+    let guess = guess_target_triple(&json);
+    project
+        .activity
+        .record(Event::Guess {
+            project: project.name.clone(),
+            os: json.os,
+            os_version: json.os_version,
+            user_agent: json.user_agent,
+            target: guess.as_ref().ok().cloned(),
+        })
+        .await;
+
+    Ok(Json(ResponseData {
+        target_triple: guess?,
+    }))
+}
+
+/// Architectures which show up in user agents but have no target, these would
+/// otherwise end up with a i686 executable they can not run.
+const UNSUPPORTED_ARCHITECTURES: [&str; 6] = ["armv6", "armv7", "ppc", "riscv", "mips", "s390x"];
+
+/// Guesses the target triple of the clients machine from the information in `json`.
+pub fn guess_target_triple(json: &PostData) -> Result<String, String> {
     // Use this pattern so that we can tweak each part of the target_triple
     // individually for weird edge cases and things.
 
-    let architecture = if json.user_agent.contains("x64")
+    if let Some(arch) = UNSUPPORTED_ARCHITECTURES
+        .iter()
+        .find(|a| json.user_agent.contains(*a))
+    {
+        error!("No target for the {arch} architecture of {json:?}");
+        return Err(format!(
+            "Sorry! There are no executables for {arch} machines."
+        ));
+    }
+
+    let architecture = if (json.user_agent.contains("aarch64") || json.user_agent.contains("arm64"))
+        && json.os != "Windows"
+    {
+        "aarch64"
+    } else if json.user_agent.contains("x64")
         || json.user_agent.contains("x86_64")
         || json.os == "Mac OS X"
     {
@@ -79,27 +337,603 @@ pub async fn get_target(Json(json): Json<PostData>) -> Result<impl IntoResponse,
         }
     };
 
-    let target_triple = vec![architecture, middle, toolchain].join("-");
+    let target_triple = [architecture, middle, toolchain].join("-");
     info!("Guessed target_triple: {target_triple}");
 
-    Ok(Json(ResponseData { target_triple }))
+    Ok(target_triple)
 }
 
+#[derive(Debug, Deserialize)]
+pub struct DownloadQuery {
+    format: Option<String>,
+    /// Pins the download to a older revision from the [History](`crate::history::History`).
+    version: Option<String>,
+}
+
+#[instrument(skip_all, fields(project = %project.name, target_triple = %target_triple))]
 pub async fn send_binary(
-    Extension(origin_url): Extension<String>,
-    Extension(cache): Extension<Arc<Mutex<Cache>>>,
-    Extension(compilation_directory): Extension<PathBuf>,
-    Extension(targets_compiling): Extension<TargetsCompiling>,
-    Extension(config): Extension<Config>,
+    Extension(project): Extension<Arc<Project>>,
+    Extension(access): Extension<Access>,
+    Extension(client): Extension<Client>,
     Path(target_triple): Path<String>,
+    Query(query): Query<DownloadQuery>,
+    method: Method,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, String> {
     info!("Recieved a request to get target triple \"{target_triple}\"");
 
-    if util::is_valid_target(&target_triple).await.is_none() {
+    let format = Format::from_query(query.format.as_deref(), &target_triple)?;
+
+    if let Some(version) = &query.version {
+        // Only the executables are kept for older revisions.
+        if format != Format::Binary {
+            return Err("Older versions can only be downloaded as executables".to_string());
+        }
+        if !project.allows(&target_triple) {
+            return Err(format!(
+                "{} is not available for {target_triple}",
+                project.name
+            ));
+        }
+
+        if let Some(refused) = client.limit(Kind::Download) {
+            return Ok(refused);
+        }
+
+        return match project.history.revision(&target_triple, version) {
+            Some((revision, path)) => {
+                info!("Returning revision {} of {target_triple}", revision.id);
+                let response = util::return_file(&path, format, &headers).await?;
+                let download = Downloaded {
+                    target_triple: &target_triple,
+                    format: format.name(),
+                    version: Some(version),
+                    cache_hit: true,
+                };
+                record_download(&project, download, (&method, &headers), &response);
+                Ok(response)
+            }
+            None => {
+                error!("No revision {version} of {target_triple} found");
+                Err(format!(
+                    "Version {version} of {target_triple} is not available"
+                ))
+            }
+        };
+    }
+
+    if let Some(refused) = admit(&access, &client, &project, &target_triple).await {
+        return Ok(refused);
+    }
+    let (path, cache_hit) = get_artifact(&project, &target_triple, format).await?;
+
+    info!("Returning file.");
+    let response = util::return_file(&path, format, &headers).await?;
+    let download = Downloaded {
+        target_triple: &target_triple,
+        format: format.name(),
+        version: None,
+        cache_hit,
+    };
+    record_download(&project, download, (&method, &headers), &response);
+    Ok(response)
+}
+
+#[instrument(skip_all, fields(project = %project.name, target_triple = %target_triple))]
+pub async fn send_deb(
+    Extension(project): Extension<Arc<Project>>,
+    Extension(access): Extension<Access>,
+    Extension(client): Extension<Client>,
+    Path(target_triple): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, String> {
+    info!("Recieved a request to get a debian package for \"{target_triple}\"");
+
+    let format = Format::from_query(Some("deb"), &target_triple)?;
+
+    if let Some(refused) = admit(&access, &client, &project, &target_triple).await {
+        return Ok(refused);
+    }
+    let (path, cache_hit) = get_artifact(&project, &target_triple, format).await?;
+
+    info!("Returning file.");
+    let response = util::return_file(&path, format, &headers).await?;
+    let download = Downloaded {
+        target_triple: &target_triple,
+        format: format.name(),
+        version: None,
+        cache_hit,
+    };
+    record_download(&project, download, (&method, &headers), &response);
+    Ok(response)
+}
+
+/// Returns the sha256 checksum of a artifact in the format `sha256sum` uses,
+/// `<checksum>  <file name>`.
+#[instrument(skip_all, fields(project = %project.name, target_triple = %target_triple))]
+pub async fn send_checksum(
+    Extension(project): Extension<Arc<Project>>,
+    Extension(access): Extension<Access>,
+    Extension(client): Extension<Client>,
+    Path(target_triple): Path<String>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Response, String> {
+    info!("Recieved a request to get the checksum of \"{target_triple}\"");
+
+    let format = Format::from_query(query.format.as_deref(), &target_triple)?;
+
+    if let Some(refused) = admit(&access, &client, &project, &target_triple).await {
+        return Ok(refused);
+    }
+    let (path, _) = get_artifact(&project, &target_triple, format).await?;
+
+    let checksum = util::checksum(&path).await?;
+    let name = package::file_name(&path);
+
+    Ok(format!("{checksum}  {name}\n").into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LatestQuery {
+    target: String,
+    current: Option<String>,
+    format: Option<String>,
+}
+
+/// A downloadable file in a [LatestRelease](`LatestRelease`).
+#[derive(Debug, Serialize)]
+pub struct ReleaseAsset {
+    name: String,
+    download_url: String,
+    checksum: String,
+}
+
+/// A patch from the clients current version to the latest one in a [LatestRelease](`LatestRelease`).
+#[derive(Debug, Serialize)]
+pub struct ReleasePatch {
+    from: String,
+    download_url: String,
+    checksum: String,
+}
+
+/// The update manifest returned by `/api/latest`.
+///
+/// Laid out like the releases `self_update` and similar crates work with, a version and
+/// a list of assets, with the asset for the requested target hoisted to the top level.
+#[derive(Debug, Serialize)]
+pub struct LatestRelease {
+    name: String,
+    version: String,
+    commit: Option<String>,
+    target: String,
+    update_available: bool,
+    download_url: String,
+    checksum: String,
+    assets: Vec<ReleaseAsset>,
+    /// Only present when a patch from the `current` version is available.
+    patch: Option<ReleasePatch>,
+}
+
+/// Reports the latest version served for a target so that clients can update themselves.
+pub async fn get_latest(
+    Extension(project): Extension<Arc<Project>>,
+    Extension(access): Extension<Access>,
+    Extension(client): Extension<Client>,
+    Query(query): Query<LatestQuery>,
+    headers: HeaderMap,
+) -> Result<Response, String> {
+    let target_triple = query.target;
+    info!("Recieved a request for the latest version of \"{target_triple}\"");
+
+    let format = Format::from_query(query.format.as_deref(), &target_triple)?;
+
+    if let Some(refused) = admit(&access, &client, &project, &target_triple).await {
+        return Ok(refused);
+    }
+    let (path, _) = get_artifact(&project, &target_triple, format).await?;
+
+    let build_info = util::build_info(&project.compilation_directory.join(&target_triple)).await?;
+    let checksum = util::checksum(&path).await?;
+    let name = package::file_name(&path);
+
+    let mut download_url = format!("{}/get_binary/{target_triple}", project.url(&headers));
+    if let Some(format) = &query.format {
+        download_url.push_str(&format!("?format={format}"));
+    }
+
+    let update_available = query
+        .current
+        .as_ref()
+        .map(|current| util::is_newer_version(&build_info.version, current))
+        .unwrap_or(true);
+
+    // Patches only exist between naked executables.
+    let mut patch = None;
+    if let (Some(current), Format::Binary) = (&query.current, format) {
+        if let Some((from, path)) = project.history.patch(&target_triple, current) {
+            patch = Some(ReleasePatch {
+                // Revision ids contain a '+', which would turn into a space in a query.
+                download_url: format!(
+                    "{}/get_patch/{target_triple}?from={}",
+                    project.url(&headers),
+                    from.id.replace('+', "%2B")
+                ),
+                from: from.id,
+                checksum: util::checksum(&path).await?,
+            });
+        }
+    }
+
+    Ok(Json(LatestRelease {
+        name: build_info.name,
+        version: build_info.version,
+        commit: build_info.commit,
+        target: target_triple,
+        update_available,
+        download_url: download_url.clone(),
+        checksum: checksum.clone(),
+        assets: vec![ReleaseAsset {
+            name,
+            download_url,
+            checksum,
+        }],
+        patch,
+    })
+    .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VersionsQuery {
+    target: Option<String>,
+}
+
+/// A revision listed by `/versions`.
+#[derive(Debug, Serialize)]
+pub struct VersionEntry {
+    id: String,
+    version: String,
+    commit: Option<String>,
+    tag: Option<String>,
+    /// Seconds since the unix epoch.
+    created: u64,
+    download_url: String,
+}
+
+/// Lists the revisions kept of every target (or just the one given by `?target=`), newest first.
+pub async fn get_versions(
+    Extension(project): Extension<Arc<Project>>,
+    Query(query): Query<VersionsQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let server = project.url(&headers);
+    let history = &project.history;
+    let targets = match query.target {
+        Some(target) if project.allows(&target) => vec![target],
+        Some(_) => Vec::new(),
+        None => history.targets(),
+    };
+
+    let versions: HashMap<String, Vec<VersionEntry>> = targets
+        .into_iter()
+        .map(|target| {
+            let entries = history
+                .revisions(&target)
+                .into_iter()
+                .map(|r| VersionEntry {
+                    download_url: format!(
+                        "{server}/get_binary/{target}?version={}",
+                        r.id.replace('+', "%2B")
+                    ),
+                    created: r
+                        .created
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0),
+                    id: r.id,
+                    version: r.info.version,
+                    commit: r.info.commit,
+                    tag: r.info.tag,
+                })
+                .collect();
+            (target, entries)
+        })
+        .collect();
+
+    Json(versions)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PatchQuery {
+    from: String,
+}
+
+/// Returns the patch from the revision given by `?from=` to the latest revision of a target.
+/// Patches are zstd compressed bsdiff patches.
+#[instrument(skip_all, fields(project = %project.name, target_triple = %target_triple))]
+pub async fn send_patch(
+    Extension(project): Extension<Arc<Project>>,
+    Extension(client): Extension<Client>,
+    Path(target_triple): Path<String>,
+    Query(query): Query<PatchQuery>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, String> {
+    info!(
+        "Recieved a request for a patch from {} for \"{target_triple}\"",
+        query.from
+    );
+
+    if !project.allows(&target_triple) {
+        return Err(format!(
+            "{} is not available for {target_triple}",
+            project.name
+        ));
+    }
+    if let Some(refused) = client.limit(Kind::Download) {
+        return Ok(refused);
+    }
+
+    match project.history.patch(&target_triple, &query.from) {
+        Some((_, path)) => {
+            let response =
+                util::return_file_as(&path, "application/octet-stream", false, &headers).await?;
+            let download = Downloaded {
+                target_triple: &target_triple,
+                format: "patch",
+                version: Some(&query.from),
+                cache_hit: true,
+            };
+            record_download(&project, download, (&method, &headers), &response);
+            Ok(response)
+        }
+        None => {
+            error!("No patch from {} for {target_triple}", query.from);
+            Err(format!(
+                "No patch from {} for {target_triple} is available",
+                query.from
+            ))
+        }
+    }
+}
+
+/// Sends plain HTTP requests to the same place over HTTPS, served on `https_port`.
+pub async fn redirect_to_https(
+    Extension(https_port): Extension<u16>,
+    Host(host): Host,
+    uri: Uri,
+) -> Result<Redirect, String> {
+    // The host ends up in the Location header, dont let anything weird through.
+    if !util::is_safe_path(&host) {
+        return Err(format!("Invalid host: {host}"));
+    }
+
+    let path_and_query = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = tls::https_location(&host, https_port, path_and_query);
+    debug!("Redirecting to {location}");
+
+    Ok(Redirect::permanent(&location))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InstallQuery {
+    prefix: Option<String>,
+}
+
+pub async fn get_install_sh(
+    Extension(project): Extension<Arc<Project>>,
+    headers: HeaderMap,
+    Query(query): Query<InstallQuery>,
+) -> Result<impl IntoResponse, String> {
+    install_script(
+        "templates/install.sh",
+        "$HOME/.local/bin",
+        util::is_safe_sh_path,
+        &project.url(&headers),
+        query,
+    )
+    .await
+}
+
+pub async fn get_install_ps1(
+    Extension(project): Extension<Arc<Project>>,
+    headers: HeaderMap,
+    Query(query): Query<InstallQuery>,
+) -> Result<impl IntoResponse, String> {
+    install_script(
+        "templates/install.ps1",
+        "$env:LOCALAPPDATA\\Programs",
+        util::is_safe_path,
+        &project.url(&headers),
+        query,
+    )
+    .await
+}
+
+/// Fills in the installer script at `template` with the address of the project
+/// and the prefix to install to, which has to be `safe` for the shell of the script.
+async fn install_script(
+    template: &str,
+    default_prefix: &str,
+    safe: fn(&str) -> bool,
+    server: &str,
+    query: InstallQuery,
+) -> Result<impl IntoResponse, String> {
+    // The prefix ends up inside the script, so dont let anything
+    // which could be interpreted by the shell through.
+    let prefix = match query.prefix {
+        Some(p) if safe(&p) => p,
+        Some(p) => {
+            error!("Refusing to create installer with prefix: {p}");
+            return Err(format!("Invalid prefix: {p}"));
+        }
+        None => default_prefix.to_string(),
+    };
+
+    let mut file = File::open(template).await.unwrap();
+    let mut script = String::new();
+    file.read_to_string(&mut script).await.unwrap();
+
+    let script = script
+        .replace("{{server}}", server)
+        .replace("{{prefix}}", &prefix);
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        script,
+    ))
+}
+
+/// What got downloaded, see [record_download](`record_download`).
+struct Downloaded<'a> {
+    target_triple: &'a str,
+    format: &'a str,
+    version: Option<&'a str>,
+    cache_hit: bool,
+}
+
+/// Records a download in the metrics and the activity log, given the `method` and `headers`
+/// of the request and the `response` sending the file. Answers to HEAD requests are no downloads.
+fn record_download(
+    project: &Project,
+    download: Downloaded<'_>,
+    (method, headers): (&Method, &HeaderMap),
+    response: &Response,
+) {
+    if method == Method::HEAD {
+        return;
+    }
+
+    project
+        .metrics
+        .request(download.target_triple, download.format);
+    let event = Event::Download {
+        project: project.name.clone(),
+        target: download.target_triple.to_string(),
+        format: download.format.to_string(),
+        version: download.version.map(str::to_string),
+        cache_hit: download.cache_hit,
+        bytes: util::body_length(response).unwrap_or(0),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string),
+    };
+    // The response is on its way already, no need to hold it up for writing the log.
+    let activity = project.activity.clone();
+    tokio::spawn(async move { activity.record(event).await }.in_current_span());
+}
+
+/// Lets a download of `target_triple` by `client` through when it is within the rate limits,
+/// and when `access` allows building it in case it has to be built first.
+/// Returns the response refusing it otherwise.
+async fn admit(
+    access: &Access,
+    client: &Client,
+    project: &Project,
+    target_triple: &String,
+) -> Option<Response> {
+    let built = project.is_built(target_triple).await;
+    if !built && !access.allows(Role::Build) {
+        return Some(auth::refuse(
+            Some(access),
+            Role::Build,
+            &format!("a build of {target_triple}"),
+        ));
+    }
+
+    client.limit(if built { Kind::Download } else { Kind::Build })
+}
+
+/// Gets the path to `target_triple` in `format`, packaging and building it when needed.
+/// Also returns whether the executable was in cache already.
+pub async fn get_artifact(
+    project: &Project,
+    target_triple: &String,
+    format: Format,
+) -> Result<(PathBuf, bool), String> {
+    if !project.allows(target_triple) {
+        error!(
+            "{target_triple} is not a allowed target of {}",
+            project.name
+        );
+        return Err(format!(
+            "{} is not available for {target_triple}",
+            project.name
+        ));
+    }
+
+    if util::is_valid_target(target_triple).await.is_none() {
         error!("Invalid target_triple: {target_triple} found!");
         return Err(format!("Invalid target triple: {target_triple}"));
     }
 
+    let (path_to_executable, executable_cached) = get_executable(project, target_triple).await?;
+
+    if format == Format::Binary {
+        return Ok((path_to_executable, executable_cached));
+    }
+
+    // Packages are cached separately from the executable they contain
+    // so that they dont have to be repackaged for every request.
+    let key = format.cache_key(target_triple);
+    let packaging = &project.packages_packaging;
+    let mut being_packaged = packaging.lock().await;
+    if let Some(path) = project.cache.lock().await.get(&key) {
+        return Ok((path, executable_cached));
+    }
+
+    let path_to_package = if being_packaged.contains(&key) {
+        // Someone else is packaging it already, wait for them like for compilations.
+        drop(being_packaged);
+        debug!("Waiting on packaging of {key}.");
+        while packaging.lock().await.contains(&key) {
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        project
+            .cache
+            .lock()
+            .await
+            .get(&key)
+            .ok_or_else(|| format!("Packaging {key} failed, please try again later!"))?
+    } else {
+        being_packaged.push(key.clone());
+        drop(being_packaged);
+
+        info!("Packaging {target_triple} as {}", format.extension());
+        let packaged = package::package(
+            format,
+            &path_to_executable,
+            target_triple,
+            &project.compilation_directory,
+            &project.config(),
+        )
+        .await;
+        if let Ok(path) = &packaged {
+            project.cache.lock().await.insert(key.clone(), path.clone());
+        }
+        packaging.lock().await.retain(|k| *k != key);
+        packaged?
+    };
+
+    Ok((path_to_package, executable_cached))
+}
+
+/// Gets the path to the compiled executable for `target_triple`, either from the cache
+/// or by cloning and compiling the repo. Also returns whether it was in cache.
+pub async fn get_executable(
+    project: &Project,
+    target_triple: &String,
+) -> Result<(PathBuf, bool), String> {
+    let Project {
+        cache,
+        compilation_directory,
+        targets_compiling,
+        history,
+        ..
+    } = project;
+
     // check if target is in cache
     // if true:
     //   return the path from cache.
@@ -113,105 +947,426 @@ pub async fn send_binary(
     // Ensure that target is not in cache already
     // if it is in cache, return the file early
     let cache_guard = cache.lock().await;
-    if let Some(path) = cache_guard.get(&target_triple) {
-        let path_but_string = &path.into_os_string().into_string().unwrap();
-        debug!("Found path: {path_but_string} in cache");
-        let name = path_but_string.split('/').last().unwrap().to_string();
-        info!("Returning file.");
-        return util::return_file(&target_triple, &name, &compilation_directory).await;
+    if let Some(path) = cache_guard.get(target_triple) {
+        debug!("Found path: {path:?} in cache");
+        return Ok((path, true));
     }
     drop(cache_guard);
 
     let mut being_compiled = targets_compiling.lock().await;
-    let path_to_executable = if being_compiled.contains(&target_triple) {
-        // Compilation is currently occuring.
-
-        // drop the guard so someone else (hopefully the one compiling)
-        // can take it and finish the occuring compilation
-        drop(being_compiled);
-
-        // busy wait for the target to leave the vector (be finished compiling)
-        debug!("Waiting on compilation lock.");
-        loop {
-            sleep(Duration::new(1, 4)).await;
-            let guard = targets_compiling.lock().await;
-            if !guard.contains(&target_triple) {
-                break;
+    let path_to_executable =
+        if being_compiled.contains(target_triple) {
+            // Compilation is currently occuring.
+
+            // drop the guard so someone else (hopefully the one compiling)
+            // can take it and finish the occuring compilation
+            drop(being_compiled);
+
+            // busy wait for the target to leave the vector (be finished compiling)
+            debug!("Waiting on compilation lock.");
+            loop {
+                sleep(Duration::new(1, 4)).await;
+                let guard = targets_compiling.lock().await;
+                if !guard.contains(target_triple) {
+                    break;
+                }
+                drop(guard);
             }
-            drop(guard);
-        }
-        debug!("compilation finished, proceeding.");
+            debug!("compilation finished, proceeding.");
 
-        // Sleep a bit extra just to be extra sure its made it into cache!
-        sleep(Duration::new(0, 2)).await;
+            // Sleep a bit extra just to be extra sure its made it into cache!
+            sleep(Duration::new(0, 2)).await;
 
-        // get the (hopefully) compiled target from the cache
-        cache.lock().await.get(&target_triple).unwrap()
-    } else {
-        // Compilation is not occuring, needs to be done.
+            // get the (hopefully) compiled target from the cache
+            cache.lock().await.get(target_triple).ok_or_else(|| {
+                format!("Compiling {target_triple} failed, please try again later!")
+            })?
+        } else {
+            // Compilation is not occuring, needs to be done.
 
-        debug!("Pushing target triple to lock vector");
-        // add the target_triple to the vector to indicate that it is being compiled.
-        being_compiled.push(target_triple.clone());
+            debug!("Pushing target triple to lock vector");
+            // add the target_triple to the vector to indicate that it is being compiled.
+            being_compiled.push(target_triple.clone());
 
-        // Drop the mutex to allow others trying to compile the same target access.
-        drop(being_compiled);
+            // Drop the mutex to allow others trying to compile the same target access.
+            drop(being_compiled);
 
-        if origin_url.contains("https://") || origin_url.contains("git@") {
-            // Clone the repo
-            info!("Cloning repo to: \"{origin_url}/{target_triple}\"");
-            if let Err(e) = util::clone_repo(&origin_url, &target_triple, &compilation_directory).await {
-                error!(e);
-                return Err(e);
-            }
+            let executable_path = match build(project, target_triple).await {
+                Ok(path) => path,
+                Err(e) => {
+                    // Clear out the failed attempt so that the next request can start over.
+                    let _ = std::fs::remove_dir_all(compilation_directory.join(target_triple));
+                    targets_compiling
+                        .lock()
+                        .await
+                        .retain(|s| s != target_triple);
+                    return Err(e);
+                }
+            };
 
-        } else {
+            info!("Compiled, now Inserting {target_triple} into cache");
+            // NOTE: this might still be premature since we have not called
+            //       return_file() but i can solve that later in that case
+            debug!("Waiting on cache lock");
+            cache
+                .lock()
+                .await
+                .insert(target_triple.clone(), executable_path.clone());
+
+            // Keep the executable around so that patches to later revisions can be made.
+            // Making the patch can take a while, so dont make the client wait for it.
+            let history = history.clone();
+            let repo_directory = compilation_directory.join(target_triple);
+            let (triple, path) = (target_triple.clone(), executable_path.clone());
+            tokio::spawn(
+                async move {
+                    let result = match util::build_info(&repo_directory).await {
+                        Ok(info) => history.record(&triple, info, &path).await.map(|_| ()),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        error!("Failed to record {triple} in history: {e}");
+                    }
+                }
+                .in_current_span(),
+            );
 
-            let local_repo = PathBuf::from(origin_url);
-            let destination = compilation_directory.join(&target_triple);
+            // Remove the compiled target_triple from the vector
+            let mut being_compiled = targets_compiling.lock().await;
+            being_compiled.retain(|s| s != target_triple);
+            // (drop the lock automatically)
 
-            info!("Copying {local_repo:?} to {destination:?}");
+            executable_path
+        };
 
-            // Overwrite and copy contents
-            let mut opts = fs_extra::dir::CopyOptions::new();
-            opts.overwrite = true;
-            opts.copy_inside = true;
+    Ok((path_to_executable, false))
+}
+
+/// Builds `target_triple`, returning the path to the executable.
+///
+/// Waits for a free build slot first when the number of builds is limited.
+#[instrument(skip_all, fields(build_id = field::Empty, target_triple = %target_triple))]
+async fn build(project: &Project, target_triple: &String) -> Result<PathBuf, String> {
+    let build_id = telemetry::new_id();
+    Span::current().record("build_id", field::display(&build_id));
+    let tracked = project.track_build(&build_id, target_triple);
 
-            // Copy local_repo to `destination`
-            fs_extra::dir::copy(local_repo, destination, &opts).unwrap();
+    // Hold on to the permit until the build is done.
+    let waiting = project.metrics.waiting();
+    let _permit = match &project.builds {
+        Some(builds) => {
+            debug!("Waiting for a free build slot for {target_triple}");
+            Some(builds.acquire().await.map_err(|e| e.to_string())?)
         }
+        None => None,
+    };
+    drop(waiting);
+    tracked.running();
 
+    let _running = project.metrics.running();
+    let started = Instant::now();
+    let result = clone_and_compile(project, target_triple).await;
+    let duration = started.elapsed();
+    project
+        .metrics
+        .build_finished(target_triple, duration, result.is_ok());
+    project
+        .activity
+        .record(Event::Build {
+            project: project.name.clone(),
+            target: target_triple.clone(),
+            duration: duration.as_secs_f64(),
+            outcome: if result.is_ok() { "success" } else { "failure" }.to_string(),
+            error: result.as_ref().err().cloned(),
+        })
+        .await;
 
-        // Compile the target, return the entire path to the the executable
-        info!("{target_triple} is not in cache, adding and compiling it now!");
-        let executable_path = util::compile(&target_triple, &compilation_directory, &config).await?;
+    result
+}
 
-        info!("Compiled, now Inserting {target_triple} into cache");
-        // NOTE: this might still be premature since we have not called
-        //       return_file() but i can solve that later in that case
-        debug!("Waiting on cache lock");
-        cache
-            .lock()
+/// Copies or clones the repo and compiles it for `target_triple`, returning the path to the executable.
+/// Gives up when the build takes longer than allowed.
+async fn clone_and_compile(project: &Project, target_triple: &String) -> Result<PathBuf, String> {
+    let Project {
+        origin_url,
+        compilation_directory,
+        ..
+    } = project;
+    let config = &project.config();
+
+    if util::is_remote(origin_url) {
+        // Clone the repo
+        info!(
+            "Cloning repo to: \"{}/{target_triple}\"",
+            git::redact(origin_url)
+        );
+        let credentials = project.git();
+        if let Err(e) = util::clone_repo(
+            origin_url,
+            target_triple,
+            compilation_directory,
+            &credentials,
+        )
+        .await
+        {
+            error!(e);
+            return Err(e);
+        }
+    } else {
+        let local_repo = PathBuf::from(origin_url);
+        let destination = compilation_directory.join(target_triple);
+
+        info!("Copying {local_repo:?} to {destination:?}");
+
+        // Overwrite and copy contents
+        let mut opts = fs_extra::dir::CopyOptions::new();
+        opts.overwrite = true;
+        opts.copy_inside = true;
+
+        // Copy local_repo to `destination`
+        fs_extra::dir::copy(local_repo, destination, &opts).unwrap();
+    }
+
+    if let Some(git_ref) = &project.git_ref {
+        info!("Checking out {git_ref} for {target_triple}");
+        util::checkout(
+            &compilation_directory.join(target_triple),
+            git_ref,
+            &project.git(),
+        )
+        .await?;
+    }
+
+    // Compile the target, return the entire path to the the executable
+    info!("{target_triple} is not in cache, adding and compiling it now!");
+    let compilation = util::compile(target_triple, compilation_directory, config);
+    match config.build_timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, compilation)
             .await
-            .insert(target_triple.clone(), executable_path.clone());
+            .map_err(|_| {
+                error!("Compiling {target_triple} took longer than {timeout:?}, aborted it");
+                format!("Compiling {target_triple} took too long!")
+            })?,
+        None => compilation.await,
+    }
+}
+
+/// Metrics about all projects in the Prometheus text format.
+pub async fn get_metrics(Extension(projects): Extension<Projects>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(&projects).await,
+    )
+}
 
-        // Remove the compiled target_triple from the vector
-        let mut being_compiled = targets_compiling.lock().await;
-        being_compiled.retain(|s| s != &target_triple);
-        // (drop the lock automatically)
+/// Liveness probe, answering at all is all it takes.
+pub async fn get_healthz() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok" }))
+}
 
-        executable_path
+/// Readiness probe, the details of every check along with `503 Service Unavailable`
+/// when one of them fails.
+pub async fn get_readyz(
+    Extension(health): Extension<Arc<Health>>,
+    Extension(projects): Extension<Projects>,
+) -> impl IntoResponse {
+    let readiness = health.readiness(&projects).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        for check in readiness.checks.iter().filter(|c| !c.ok) {
+            error!("Not ready, {} check failed: {}", check.name, check.detail);
+        }
+        StatusCode::SERVICE_UNAVAILABLE
     };
 
-    let name = path_to_executable
-        .into_os_string()
-        .into_string()
-        .unwrap()
-        .split('/')
-        .last()
-        .unwrap()
-        .to_string();
+    (status, Json(readiness))
+}
 
-    info!("Returning file.");
-    util::return_file(&target_triple, &name, &compilation_directory).await
+/// The most recent guesses, downloads and builds, newest first.
+/// Filtered by the `event`, `project` and `target` query parameters, `limit` limits how many.
+pub async fn get_activity(
+    Extension(activity): Extension<Arc<ActivityLog>>,
+    Query(filter): Query<Filter>,
+) -> impl IntoResponse {
+    Json(activity.recent(filter).await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdminQuery {
+    /// Limits what is shown or evicted to a single project.
+    project: Option<String>,
+}
+
+impl AdminQuery {
+    /// The projects the query is about, `404 Not Found` for a unknown one.
+    fn projects<'a>(
+        &self,
+        projects: &'a Projects,
+    ) -> Result<Vec<&'a Arc<Project>>, (StatusCode, String)> {
+        match &self.project {
+            Some(name) => Ok(vec![admin::project(projects, name)?]),
+            None => Ok(projects.values().collect()),
+        }
+    }
+}
+
+/// Everything in cache, of every project or the one given through `project`.
+pub async fn get_admin_cache(
+    Extension(projects): Extension<Projects>,
+    Query(query): Query<AdminQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut entries = Vec::new();
+    for project in query.projects(&projects)? {
+        let cache = project.cache.lock().await.entries();
+        entries.extend(cache.into_iter().map(|entry| CachedEntry {
+            project: project.name.clone(),
+            entry,
+        }));
+    }
+
+    Ok(Json(entries))
+}
+
+/// Empties the cache of every project or the one given through `project`,
+/// returning what got evicted.
+pub async fn delete_admin_cache(
+    Extension(projects): Extension<Projects>,
+    Query(query): Query<AdminQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut evicted = Vec::new();
+    for project in query.projects(&projects)? {
+        let keys = project.cache.lock().await.clear();
+        info!("{}: Evicted {keys:?} through the admin API", project.name);
+        evicted.extend(keys.into_iter().map(|key| Evicted {
+            project: project.name.clone(),
+            key,
+        }));
+    }
+
+    Ok(Json(evicted))
+}
+
+/// Evicts a single key, a target triple or a package such as `x86_64-unknown-linux-gnu.deb`.
+pub async fn delete_admin_cache_entry(
+    Extension(projects): Extension<Projects>,
+    Path((name, key)): Path<(String, String)>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let project = admin::project(&projects, &name)?;
+    if !project.cache.lock().await.remove(&key) {
+        return Err((StatusCode::NOT_FOUND, format!("{key} is not in cache")));
+    }
+
+    info!("{name}: Evicted {key} through the admin API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Evicts `target_triple` along with its packages and builds it again in the background.
+pub async fn post_admin_rebuild(
+    Extension(projects): Extension<Projects>,
+    Path((name, target_triple)): Path<(String, String)>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let project = admin::project(&projects, &name)?.clone();
+    if !project.allows(&target_triple) || util::is_valid_target(&target_triple).await.is_none() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{target_triple} can not be built for {name}"),
+        ));
+    }
+    if project
+        .targets_compiling
+        .lock()
+        .await
+        .contains(&target_triple)
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("{target_triple} is being built already"),
+        ));
+    }
+
+    {
+        let cache = project.cache.lock().await;
+        let package_prefix = format!("{target_triple}.");
+        for entry in cache.entries() {
+            if entry.key == target_triple || entry.key.starts_with(&package_prefix) {
+                cache.remove(&entry.key);
+            }
+        }
+    }
+
+    info!("{name}: Rebuilding {target_triple} through the admin API");
+    let target = target_triple.clone();
+    tokio::spawn(
+        async move {
+            if let Err(e) = get_executable(&project, &target).await {
+                error!("Rebuilding {target} failed: {e}");
+            }
+        }
+        .in_current_span(),
+    );
+
+    Ok((
+        StatusCode::ACCEPTED,
+        format!("Rebuilding {target_triple} of {name}"),
+    ))
+}
+
+/// The builds in progress of every project, oldest first.
+pub async fn get_admin_builds(Extension(projects): Extension<Projects>) -> impl IntoResponse {
+    let builds: Vec<Build> = projects
+        .values()
+        .flat_map(|project| {
+            project.in_flight().into_iter().map(|build| Build {
+                project: project.name.clone(),
+                build,
+            })
+        })
+        .collect();
+
+    Json(builds)
+}
+
+/// How many times every target got downloaded in every format since starting, by project.
+pub async fn get_admin_downloads(Extension(projects): Extension<Projects>) -> impl IntoResponse {
+    let downloads: Vec<Downloads> = projects
+        .values()
+        .flat_map(|project| {
+            project
+                .metrics
+                .downloads()
+                .into_iter()
+                .map(|((target_triple, format), count)| Downloads {
+                    project: project.name.clone(),
+                    target_triple,
+                    format,
+                    count,
+                })
+        })
+        .collect();
+
+    Json(downloads)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogsQuery {
+    /// Only return the lines after this one.
+    #[serde(default)]
+    after: u64,
+}
+
+/// The last lines logged, oldest first.
+pub async fn get_admin_logs(
+    Extension(logs): Extension<LogBuffer>,
+    Query(query): Query<LogsQuery>,
+) -> impl IntoResponse {
+    Json(logs.after(query.after))
+}
+
+/// The admin dashboard, which asks for the token and gets everything it shows from the admin API.
+pub async fn get_admin_dashboard() -> Result<Html<String>, String> {
+    tokio::fs::read_to_string("templates/admin.html")
+        .await
+        .map(Html)
+        .map_err(|e| format!("Failed to read the dashboard: {e}"))
 }
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// The signals asking the process to stop, SIGINT (Ctrl-C) and SIGTERM.
+struct Signals {
+    #[cfg(unix)]
+    interrupt: Option<tokio::signal::unix::Signal>,
+    #[cfg(unix)]
+    terminate: Option<tokio::signal::unix::Signal>,
+}
+
+impl Signals {
+    /// Starts listening, the same listeners have to be used throughout since new ones
+    /// would see the signals received before they got created.
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let listen = |kind: SignalKind, name: &str| match signal(kind) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    error!("Failed to listen for {name}: {e}");
+                    None
+                }
+            };
+            Signals {
+                interrupt: listen(SignalKind::interrupt(), "SIGINT"),
+                terminate: listen(SignalKind::terminate(), "SIGTERM"),
+            }
+        }
+        #[cfg(not(unix))]
+        Signals {}
+    }
+
+    /// Resolves once the process is asked to stop.
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        async fn recv(signal: &mut Option<tokio::signal::unix::Signal>) {
+            match signal {
+                Some(s) => {
+                    s.recv().await;
+                }
+                None => std::future::pending::<()>().await,
+            }
+        }
+
+        tokio::select! {
+            _ = recv(&mut self.interrupt) => info!("Received SIGINT"),
+            _ = recv(&mut self.terminate) => info!("Received SIGTERM"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+        info!("Received Ctrl-C");
+    }
+}
+
+/// Lets the servers know when to stop accepting connections and how long the ones
+/// still open get to finish.
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: watch::Receiver<bool>,
+    /// How long to wait for open connections, `None` waits for as long as they take.
+    pub drain: Option<Duration>,
+}
+
+impl Shutdown {
+    /// Starts listening for SIGINT and SIGTERM, after which the servers should stop.
+    /// A second one exits right away.
+    pub fn listen(drain: Option<Duration>) -> Self {
+        let (sender, requested) = watch::channel(false);
+        let mut signals = Signals::new();
+
+        tokio::spawn(async move {
+            signals.recv().await;
+            match drain {
+                Some(drain) => info!(
+                    "Shutting down, waiting up to {} seconds for downloads and builds to finish",
+                    drain.as_secs()
+                ),
+                None => info!("Shutting down, waiting for downloads and builds to finish"),
+            }
+            let _ = sender.send(true);
+
+            signals.recv().await;
+            warn!("Asked to stop again, exiting right away");
+            std::process::exit(1);
+        });
+
+        Shutdown { requested, drain }
+    }
+
+    /// Resolves once shutting down got requested.
+    pub async fn requested(mut self) {
+        while !*self.requested.borrow() {
+            if self.requested.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Resolves once the drain period after shutting down got requested is over,
+    /// never when there is no limit to it.
+    pub async fn drained(self) {
+        let drain = self.drain;
+        self.requested().await;
+        match drain {
+            Some(drain) => tokio::time::sleep(drain).await,
+            None => std::future::pending::<()>().await,
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    fmt::{Debug, Write},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime},
+};
+
+use axum::{middleware::Next, response::Response};
+use http::{HeaderValue, Request};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use serde::Serialize;
+use tracing::{
+    debug, field, field::Visit, info, info_span, metadata::LevelFilter, span, Event, Instrument,
+    Subscriber,
+};
+use tracing_subscriber::{
+    fmt,
+    layer::{Context, Layer, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+use crate::{
+    config::{LogFormat, Settings},
+    util,
+};
+
+/// How many lines the [LogBuffer](`LogBuffer`) keeps around.
+const LOG_LINES: usize = 1000;
+
+/// The header holding the id of a request, taken from the client when it sends a usable one.
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// Keeps the OpenTelemetry exporter around, when exporting.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+    /// The last lines logged, for the admin dashboard.
+    pub logs: LogBuffer,
+}
+
+impl Telemetry {
+    /// Sends off the spans which have not been exported yet.
+    pub fn shut_down(self) {
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to export the last spans: {e}");
+            }
+        }
+    }
+}
+
+/// Sets up logging to stdout as text or JSON, and exporting spans through OTLP when
+/// `log.otlp_endpoint` is set.
+pub fn init(settings: &Settings) -> Result<Telemetry, String> {
+    let log_level = if settings.debug {
+        LevelFilter::DEBUG
+    } else {
+        LevelFilter::INFO
+    };
+
+    // Only one of them gets used, they just are different types.
+    let text = (settings.log.format == LogFormat::Text)
+        .then(|| fmt::layer().with_level(true).with_target(false));
+    let json = (settings.log.format == LogFormat::Json).then(|| {
+        fmt::layer()
+            .json()
+            .with_target(false)
+            .with_current_span(false)
+            .with_span_list(true)
+    });
+
+    let provider = match &settings.log.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(traces_endpoint(endpoint))
+                .build()
+                .map_err(|e| format!("Failed to set up exporting to {endpoint}: {e}"))?;
+            let resource = Resource::builder()
+                .with_service_name(settings.log.service_name.clone())
+                .build();
+            Some(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(resource)
+                    .build(),
+            )
+        }
+        None => None,
+    };
+    let otel = provider
+        .as_ref()
+        .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("gload")));
+
+    let logs = LogBuffer::new(LOG_LINES);
+
+    tracing_subscriber::registry()
+        .with(log_level)
+        .with(text)
+        .with(json)
+        .with(otel)
+        .with(logs.clone())
+        .try_init()
+        .map_err(|e| e.to_string())?;
+
+    info!("Log level set to: {log_level}");
+    if let Some(endpoint) = &settings.log.otlp_endpoint {
+        info!("Exporting spans to {}", traces_endpoint(endpoint));
+    }
+
+    Ok(Telemetry { provider, logs })
+}
+
+/// Where spans get sent to, given the base URL of a collector.
+pub fn traces_endpoint(endpoint: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    if endpoint.ends_with("/v1/traces") {
+        endpoint.to_string()
+    } else {
+        format!("{endpoint}/v1/traces")
+    }
+}
+
+/// A new random id for a request or a build, 16 hex digits.
+pub fn new_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // Every RandomState is seeded differently, the counter makes sure of it.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+/// Whether `id` sent by a client is fit to show up in the logs.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Runs every request in a span carrying its id, so that the logs of concurrent requests
+/// can be told apart. The id gets sent back in `x-request-id`.
+pub async fn request_span<B>(request: Request<B>, next: Next<B>) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|h| h.to_str().ok())
+        .filter(|id| is_valid_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(new_id);
+
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        status = field::Empty,
+    );
+
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+
+    let status = response.status().as_u16();
+    span.record("status", status);
+    span.in_scope(|| debug!("Responded {status} after {:?}", started.elapsed()));
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+/// A line of the logs, as kept by the [LogBuffer](`LogBuffer`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LogLine {
+    /// Counts up from 1, for asking for the lines after it.
+    pub seq: u64,
+    /// In RFC 3339 format.
+    pub timestamp: String,
+    pub level: String,
+    /// The spans it got logged in, such as `request{request_id=..}:build{build_id=..}`.
+    pub spans: String,
+    pub message: String,
+}
+
+/// Keeps the last lines logged in memory, as a [Layer](`Layer`) next to the ones writing them out.
+/// Clones share the lines.
+#[derive(Clone)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<LogLine>>>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        LogBuffer {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, level: &str, spans: String, message: String) {
+        let mut lines = self.lines.lock().unwrap();
+        let seq = lines.back().map(|l| l.seq + 1).unwrap_or(1);
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(LogLine {
+            seq,
+            timestamp: util::rfc3339(SystemTime::now()),
+            level: level.to_string(),
+            spans,
+            message,
+        });
+    }
+
+    /// The lines kept after `seq`, oldest first.
+    pub fn after(&self, seq: u64) -> Vec<LogLine> {
+        let lines = self.lines.lock().unwrap();
+        lines.iter().filter(|l| l.seq > seq).cloned().collect()
+    }
+}
+
+/// Formats the fields of a span or event like the text logs do, `key=value` separated by spaces.
+/// The message of a event is kept apart.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &field::Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &field::Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+            return;
+        }
+        if !self.fields.is_empty() {
+            self.fields.push(' ');
+        }
+        let _ = write!(self.fields, "{}={value:?}", field.name());
+    }
+}
+
+/// The formatted fields of a span, kept in its extensions.
+struct SpanFields(Fields);
+
+impl<S> Layer<S> for LogBuffer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(SpanFields(fields)) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut spans = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let fields = extensions
+                    .get::<SpanFields>()
+                    .map(|f| f.0.fields.as_str())
+                    .unwrap_or_default();
+                spans.push(format!("{}{{{fields}}}", span.name()));
+            }
+        }
+
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let mut message = fields.message;
+        if !fields.fields.is_empty() {
+            let _ = write!(message, " {}", fields.fields);
+        }
+
+        self.push(event.metadata().level().as_str(), spans.join(":"), message);
+    }
+}
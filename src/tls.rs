@@ -0,0 +1,81 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use axum::{middleware::Next, response::Response};
+use axum_server::tls_rustls::RustlsConfig;
+use http::{header, HeaderValue, Request};
+use tracing::{error, info};
+
+/// Loads the PEM encoded certificate chain at `cert` and its private key at `key`.
+pub async fn load(cert: &Path, key: &Path) -> Result<RustlsConfig, String> {
+    RustlsConfig::from_pem_file(cert, key)
+        .await
+        .map_err(|e| format!("Failed to load certificate {cert:?} with key {key:?}: {e}"))
+}
+
+/// Reloads the certificate into `config` whenever `cert` or `key` changes, checking every
+/// `interval`, so that renewed certificates get picked up without restarting.
+///
+/// A certificate which fails to load is logged and the current one is kept.
+pub async fn watch(config: RustlsConfig, cert: PathBuf, key: PathBuf, interval: Duration) {
+    let modified = || -> Vec<Option<SystemTime>> {
+        [&cert, &key]
+            .iter()
+            .map(|f| f.metadata().and_then(|m| m.modified()).ok())
+            .collect()
+    };
+
+    let mut last = modified();
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let current = modified();
+        if current == last {
+            continue;
+        }
+        last = current;
+
+        match config.reload_from_pem_file(&cert, &key).await {
+            Ok(_) => info!("Reloaded certificate {cert:?}"),
+            Err(e) => error!("Failed to reload certificate {cert:?}, keeping the current one: {e}"),
+        }
+    }
+}
+
+/// Where to redirect a plain HTTP request for `path_and_query` on `host` to, when serving
+/// HTTPS on `port`.
+pub fn https_location(host: &str, port: u16, path_and_query: &str) -> String {
+    // Drop the port the request came in on, IPv6 addresses contain colons too.
+    let host = match host.rsplit_once(':') {
+        Some((h, p)) if !p.contains(']') && p.chars().all(|c| c.is_ascii_digit()) => h,
+        _ => host,
+    };
+
+    if port == 443 {
+        format!("https://{host}{path_and_query}")
+    } else {
+        format!("https://{host}:{port}{path_and_query}")
+    }
+}
+
+/// Fills in the headers [server_url](`crate::util::server_url`) goes by for requests which came in
+/// over HTTPS, so that the urls handed out (install scripts, update manifests and so on) are right.
+///
+/// The scheme is always https and HTTP/2 clients send the host as part of the uri
+/// instead of in a `Host` header.
+pub async fn https_headers<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let authority = request
+        .uri()
+        .authority()
+        .and_then(|a| HeaderValue::from_str(a.as_str()).ok());
+
+    let headers = request.headers_mut();
+    headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+    if let (false, Some(authority)) = (headers.contains_key(header::HOST), authority) {
+        headers.insert(header::HOST, authority);
+    }
+
+    next.run(request).await
+}
@@ -1,11 +1,31 @@
-use std::{fs, io::ErrorKind, path::PathBuf, process::Stdio};
-use tokio::io::AsyncReadExt;
-
-use axum::body::StreamBody;
-use http::{header, HeaderMap, HeaderValue};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use axum::{
+    body::{self, Empty, HttpBody, StreamBody},
+    response::Response,
+};
+use http::{header, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
 use tokio::{fs::File, process::Command};
 use tokio_util::io::ReaderStream;
-use tracing::{debug, error};
+use tracing::{debug, error, instrument};
+
+use crate::{
+    config::{Backend, GitSettings},
+    encoding::{self, Encoding},
+    git,
+    manifest::Manifest,
+    package::Format,
+};
 
 pub async fn is_valid_target(target_triple: &String) -> Option<String> {
     debug!("Trying to validate target: {target_triple}");
@@ -50,66 +70,363 @@ pub async fn is_valid_target(target_triple: &String) -> Option<String> {
 
 /// Gets file contents and returns them as a axum-returnable type.
 ///
-/// Uses the given `target_triple` and `executable_name` to find the executable file to return and
-/// creates a axum-returnable representing the executable file.
+/// Opens the file at `path` and creates a response representing it, sent as a attachment
+/// with the content type of the `format`. Honours the conditional (`If-None-Match`,
+/// `If-Modified-Since`) and `Range` headers in `request_headers` so that downloads can be resumed
+/// and revalidated, the `ETag` is the checksum of the file. Naked executables get compressed
+/// according to `Accept-Encoding`, packages already are compressed.
+#[instrument(skip(request_headers))]
 pub async fn return_file(
-    target_triple: &String,
-    executable_name: &String,
-    compilation_directory: &PathBuf,
-) -> Result<(HeaderMap, StreamBody<ReaderStream<File>>), String> {
-    // At this point we can be sure that the file exists
-    // and that we can grab it safely (hopefully)!
-    let fname = compilation_directory
-        .join(target_triple)
-        .join("target")
-        .join(target_triple)
-        .join("release")
-        .join(executable_name);
+    path: &Path,
+    format: Format,
+    request_headers: &HeaderMap,
+) -> Result<Response, String> {
+    return_file_as(
+        path,
+        format.content_type(),
+        format == Format::Binary,
+        request_headers,
+    )
+    .await
+}
 
-    debug!("Returning filename: {fname:?}");
-    let file = match File::open(&fname).await {
+/// Like [return_file](`return_file`) but for files which are not a [Format](`Format`),
+/// `compress` decides whether `Accept-Encoding` is honoured.
+pub async fn return_file_as(
+    path: &Path,
+    content_type: &str,
+    compress: bool,
+    request_headers: &HeaderMap,
+) -> Result<Response, String> {
+    let encoding = request_headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|h| h.to_str().ok())
+        .filter(|_| compress)
+        .and_then(Encoding::negotiate);
+
+    // Ranges and ETags apply to the compressed file when sending a compressed file,
+    // so just swap it in for the original.
+    let (file_path, etag) = match encoding {
+        Some(e) => (
+            encoding::compressed(path, e).await?,
+            format!("\"{}-{}\"", checksum(path).await?, e.extension()),
+        ),
+        None => (path.to_path_buf(), format!("\"{}\"", checksum(path).await?)),
+    };
+
+    debug!("Returning filename: {file_path:?}");
+    let mut file = match File::open(&file_path).await {
         Ok(f) => f,
         Err(_) => {
-            error!("Failed to open {fname:?}");
-            return Err(format!("Error: Failed to open file: {fname:?}!"));
+            error!("Failed to open {path:?}");
+            return Err(format!("Error: Failed to open file: {path:?}!"));
         }
     };
 
-    // make the file into a body through axum and magic
-    let stream = ReaderStream::new(file);
-    let body = StreamBody::new(stream);
+    let metadata = file.metadata().await.map_err(|e| e.to_string())?;
+    let length = metadata.len();
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    let last_modified = httpdate::fmt_http_date(modified);
 
     // Create appropriate headers
-    let disposition =
-        HeaderValue::from_str(&format!("attachment; filename={}", &executable_name)).unwrap();
-    let ctype = HeaderValue::from_str("").unwrap();
-    let mut headers = HeaderMap::new();
-    headers.insert(header::CONTENT_TYPE, ctype);
-    headers.insert(header::CONTENT_DISPOSITION, disposition);
-
-    Ok((headers, body))
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename={file_name}"),
+        )
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, &last_modified);
+
+    if compress {
+        response = response.header(header::VARY, "Accept-Encoding");
+    }
+    if let Some(e) = encoding {
+        response = response.header(header::CONTENT_ENCODING, e.name());
+    }
+
+    if is_not_modified(request_headers, &etag, modified) {
+        debug!("{path:?} was not modified");
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(body::boxed(Empty::new()))
+            .unwrap());
+    }
+
+    // A Range only applies when the client still has the same file as us,
+    // as indicated by If-Range, otherwise they get the whole thing.
+    let range = request_headers
+        .get(header::RANGE)
+        .and_then(|h| h.to_str().ok())
+        .filter(|_| {
+            request_headers
+                .get(header::IF_RANGE)
+                .and_then(|h| h.to_str().ok())
+                .map(|r| r == etag || r == last_modified)
+                .unwrap_or(true)
+        });
+
+    let range = match range.map(|r| parse_range(r, length)) {
+        Some(Ok(range)) => range,
+        Some(Err(e)) => {
+            debug!("Unsatisfiable range for {path:?}: {e}");
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{length}"))
+                .body(body::boxed(Empty::new()))
+                .unwrap());
+        }
+        None => None,
+    };
+
+    // make the file into a body through axum and magic
+    let body = if let Some((start, end)) = range {
+        debug!("Returning bytes {start}-{end} of {path:?}");
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(|e| e.to_string())?;
+        response = response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {start}-{end}/{length}"),
+            )
+            .header(header::CONTENT_LENGTH, end - start + 1);
+        body::boxed(StreamBody::new(ReaderStream::new(
+            file.take(end - start + 1),
+        )))
+    } else {
+        response = response
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, length);
+        body::boxed(StreamBody::new(ReaderStream::new(file)))
+    };
+
+    Ok(response.body(body).unwrap())
 }
 
-/// Destroys and restores the compilation_directory folder so that it can be used again.
-pub fn restore_compilation_directory(compilation_directory: &PathBuf) -> Result<(), String> {
-    debug!("Checking repo availiability...");
+/// The length of the body of `response`, going by its `Content-Length`.
+pub fn body_length(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse().ok())
+        .or_else(|| response.body().size_hint().exact())
+}
+
+/// Formats `time` as a RFC 3339 timestamp in UTC with millisecond precision,
+/// like `2022-07-03T14:05:09.120Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let time_of_day = seconds % 86400;
+
+    // Converts days since the epoch to a date in the proleptic gregorian calendar,
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
 
-    if let Err(e) = fs::remove_dir_all(compilation_directory) {
-        let kind = e.kind();
+/// Checks the conditional headers of a request against the `etag` and `modified` time
+/// of the file being requested. `If-None-Match` takes precedence over `If-Modified-Since`.
+fn is_not_modified(request_headers: &HeaderMap, etag: &str, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = request_headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+    {
+        return if_none_match
+            .split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag);
+    }
 
-        // Handle errors which are recoverable (such as NotFound)
-        // discreetly, otherwise panic. This is because NotFound
-        // really doesnt matter to us at this point
-        match kind {
-            ErrorKind::NotFound => {}
-            _ => {
-                return Err(e.to_string());
+    request_headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| httpdate::parse_http_date(h).ok())
+        .map(|since| {
+            // HTTP dates only have second precision
+            let modified = modified
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let since = since
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            modified <= since
+        })
+        .unwrap_or(false)
+}
+
+/// Parses the value of a `Range` header for a file of `length` bytes.
+///
+/// Returns the inclusive start and end of the requested range, `None` if the header should be
+/// ignored (multiple ranges or something other than bytes) and a error if the range can not
+/// be satisfied.
+pub fn parse_range(range: &str, length: u64) -> Result<Option<(u64, u64)>, String> {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return Ok(None),
+    };
+
+    let (start, end) = match spec.split_once('-') {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=-n, the last n bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || length == 0 {
+                return Err(format!("Empty suffix range: {range}"));
+            }
+            (length - suffix.min(length), length - 1)
+        }
+        // bytes=n-, everything from n
+        (Ok(start), Err(_)) if end.is_empty() => (start, length.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+        _ => return Ok(None),
+    };
+
+    if range.0 >= length {
+        return Err(format!("Range {range:?} starts after the end of the file"));
+    }
+
+    Ok(Some(range))
+}
+
+/// Creates a unique path next to `path` for writing to before moving the result into place,
+/// so that nobody gets served a half written file.
+pub fn temporary_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.part", COUNTER.fetch_add(1, Ordering::Relaxed)));
+    PathBuf::from(temporary)
+}
+
+/// Gets the hex encoded sha256 checksum of the file at `path`.
+///
+/// The checksum is computed once and stored next to the file as `<file>.sha256`,
+/// subsequent calls just read it back.
+pub async fn checksum(path: &Path) -> Result<String, String> {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".sha256");
+    let sidecar = PathBuf::from(sidecar);
+
+    if let Ok(checksum) = tokio::fs::read_to_string(&sidecar).await {
+        return Ok(checksum);
+    }
+
+    let p = path.to_path_buf();
+    let checksum = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut fs::File::open(p)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| {
+        error!("Failed to compute checksum of {path:?}: {e}");
+        format!("Error: Failed to compute checksum of {path:?}!")
+    })?;
+
+    if let Err(e) = tokio::fs::write(&sidecar, &checksum).await {
+        debug!("Failed to store checksum in {sidecar:?}: {e}");
+    }
+
+    Ok(checksum)
+}
+
+/// Removes a artifact which went out of cache along with everything stored next to it.
+///
+/// Executables are stored in their own directory while packages are single files
+/// with things like their checksums stored as `<file>.<extension>` next to them.
+pub fn remove_artifact(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() {
+        return fs::remove_dir_all(path);
+    }
+
+    if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+        let prefix = format!("{}.", name.to_string_lossy());
+        for entry in fs::read_dir(parent)?.filter_map(|e| e.ok()) {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                fs::remove_file(entry.path())?;
             }
         }
     }
 
-    if let Err(e) = fs::create_dir(compilation_directory) {
-        return Err(e.to_string());
+    fs::remove_file(path)
+}
+
+/// Empties the compilation_directory folder so that it can be used again, creating it if needed.
+///
+/// Whatever is named in `keep` is left in place, along with the files stored next to it
+/// (see [remove_artifact](`remove_artifact`)), everything else is a leftover from a earlier run.
+pub fn restore_compilation_directory(
+    compilation_directory: &Path,
+    keep: &[String],
+) -> Result<(), String> {
+    debug!("Checking repo availiability...");
+
+    let entries = match fs::read_dir(compilation_directory) {
+        Ok(entries) => entries,
+        // Handle errors which are recoverable (such as NotFound)
+        // discreetly, NotFound really doesnt matter to us at this point
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            fs::create_dir_all(compilation_directory).map_err(|e| e.to_string())?;
+            debug!("Succesfully restored repo");
+            return Ok(());
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+
+    for entry in entries {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let kept = keep
+            .iter()
+            .any(|k| name == *k || name.starts_with(&format!("{k}.")));
+        if kept {
+            continue;
+        }
+
+        let path = entry.path();
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        removed.map_err(|e| format!("Failed to remove {path:?}: {e}"))?;
     }
 
     debug!("Succesfully restored repo");
@@ -117,49 +434,158 @@ pub fn restore_compilation_directory(compilation_directory: &PathBuf) -> Result<
     Ok(())
 }
 
-/// Should clone the `origin_url` into `compilation_directory/target_name`.
+/// Should clone the `origin_url` into `compilation_directory/target_name`, with `credentials`
+/// for private repos.
+///
+/// What git had to say about failing gets logged, with the credentials scrubbed from it.
+#[instrument(skip_all, fields(origin_url = %git::redact(origin_url), target_name = %target_name))]
 pub async fn clone_repo(
     origin_url: &String,
     target_name: &String,
-    compilation_directory: &PathBuf,
+    compilation_directory: &Path,
+    credentials: &GitSettings,
 ) -> Result<(), String> {
-    let git_output = Command::new("git")
+    let output = git::command(origin_url, credentials)
         .arg("clone")
+        .arg("--quiet")
         .arg(origin_url)
         .arg(compilation_directory.join(target_name))
         .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn();
-
-    // NOTE: could maybe simplify this match by using .and_then() or something
-    //       since we just need to apply a function to the value if its Ok(),
-    //       otherwise we should print the error.
-    match git_output {
-        Ok(mut o) => {
-            // Check the git output
-            if o.wait().await.unwrap().code().unwrap() != 0 {
-                error!("Error when trying to clone git repository \"{origin_url}\"");
-                return Err(
-                    "Error when trying to clone git repository, please try again later!"
-                        .to_string(),
-                );
-            }
-        }
-        Err(e) => {
-            error!("Error cloning the repo:  {e:?}");
-            return Err(format!("Error cloning the repo: {e:?}"));
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| {
+            error!("Error cloning the repo: {e:?}");
+            format!("Error cloning the repo: {e:?}")
+        })?;
+
+    if !output.status.success() {
+        let stderr = git::scrub(&String::from_utf8_lossy(&output.stderr), credentials);
+        let url = git::redact(origin_url);
+        error!(
+            "Failed to clone \"{url}\" ({}):\n{}",
+            output.status,
+            stderr.trim_end()
+        );
+        return Err(format!(
+            "Failed to clone the repo: {}",
+            git::last_line(&stderr)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks out `git_ref` (a branch, tag or commit) in the repo at `repo_directory`.
+pub async fn checkout(
+    repo_directory: &Path,
+    git_ref: &str,
+    credentials: &GitSettings,
+) -> Result<(), String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo_directory)
+        .arg("checkout")
+        .arg("--quiet")
+        .arg(git_ref)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| format!("Error checking out {git_ref}: {e:?}"))?;
+
+    if !output.status.success() {
+        let stderr = git::scrub(&String::from_utf8_lossy(&output.stderr), credentials);
+        error!(
+            "Failed to check out \"{git_ref}\" in {repo_directory:?} ({}):\n{}",
+            output.status,
+            stderr.trim_end()
+        );
+        return Err(format!(
+            "Failed to check out {git_ref}: {}",
+            git::last_line(&stderr)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether `origin_url` points to a repo which has to be cloned rather than copied.
+pub fn is_remote(origin_url: &str) -> bool {
+    origin_url.contains("https://") || origin_url.contains("git@")
+}
+
+/// Checks that the repo at `origin_url` can be cloned (or copied) from with `credentials`,
+/// giving up on remote repos after `timeout`.
+pub async fn source_reachable(
+    origin_url: &str,
+    credentials: &GitSettings,
+    timeout: Duration,
+) -> Result<(), String> {
+    if !is_remote(origin_url) {
+        return match Path::new(origin_url).is_dir() {
+            true => Ok(()),
+            false => Err(format!("{origin_url:?} is not a directory")),
+        };
+    }
+
+    let output = git::command(origin_url, credentials)
+        .arg("ls-remote")
+        .arg("--quiet")
+        .arg(origin_url)
+        .arg("HEAD")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output();
+
+    let url = git::redact(origin_url);
+    match tokio::time::timeout(timeout, output).await {
+        Ok(Ok(output)) if output.status.success() => Ok(()),
+        Ok(Ok(output)) => {
+            let stderr = git::scrub(&String::from_utf8_lossy(&output.stderr), credentials);
+            Err(format!(
+                "git ls-remote {url} failed: {}",
+                git::last_line(&stderr)
+            ))
         }
+        Ok(Err(e)) => Err(format!("Error running git ls-remote {url}: {e}")),
+        Err(_) => Err(format!("git ls-remote {url} timed out")),
     }
+}
+
+/// Checks that files can be created in `directory`.
+pub fn check_writable(directory: &Path) -> Result<(), String> {
+    let probe = temporary_path(&directory.join(".probe"));
+    fs::write(&probe, b"").map_err(|e| format!("{directory:?} is not writable: {e}"))?;
+    let _ = fs::remove_file(&probe);
 
     Ok(())
 }
 
+/// The space available to us on the filesystem holding `path` in bytes,
+/// `None` when it can not be found out.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    let stat = nix::sys::statvfs::statvfs(path).ok()?;
+    Some(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
 /// Tries to compile for the specified target_triple.
 /// Returns the path to the compiled executable file.
+#[instrument(skip_all)]
 pub async fn compile(
     target_triple: &String,
-    compilation_directory: &PathBuf,
-    config: &Config
+    compilation_directory: &Path,
+    config: &Config,
 ) -> Result<PathBuf, String> {
     let (stdout, stderr) = if config.debug {
         (Stdio::inherit(), Stdio::inherit())
@@ -168,7 +594,7 @@ pub async fn compile(
     };
 
     // need some way to get the "building" part of cargo output
-    let s = Command::new("cross")
+    let s = Command::new(config.backend.command())
         .arg("b")
         .arg("--release")
         .arg("--manifest-path")
@@ -176,16 +602,21 @@ pub async fn compile(
         .arg(format!("--target={target_triple}"))
         .stdout(stdout)
         .stderr(stderr)
+        // Builds which time out get dropped, dont leave them running.
+        .kill_on_drop(true)
         .status()
         .await
-        .expect("Failed to use cross.");
+        .map_err(|e| format!("Failed to run {}: {e}", config.backend.command()))?;
 
     // NOTE: This does NOT seem that healthy tbh
     if let Some(code) = s.code() {
         debug!("return code: {code}");
         if code > 0 {
-            error!("Cross returned error code: {code}");
-            return Err(format!("Cross return error code: {code}"));
+            error!("{} returned error code: {code}", config.backend.command());
+            return Err(format!(
+                "{} return error code: {code}",
+                config.backend.command()
+            ));
         }
     }
 
@@ -196,6 +627,7 @@ pub async fn compile(
     };
 
     let executable_path = compilation_directory
+        .join(target_triple)
         .join("target")
         .join(target_triple)
         .join("release")
@@ -204,20 +636,72 @@ pub async fn compile(
     Ok(executable_path)
 }
 
+/// Version information about the source a target got compiled from.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildInfo {
+    /// The package `name` from the repo's Cargo.toml.
+    pub name: String,
+    /// The `version` from the repo's Cargo.toml.
+    pub version: String,
+    /// The commit the repo was at, if it is a git repo.
+    pub commit: Option<String>,
+    /// The tag pointing at `commit`, if there is one.
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+/// Reads the [BuildInfo](`BuildInfo`) of the repo in `repo_directory`.
+pub async fn build_info(repo_directory: &Path) -> Result<BuildInfo, String> {
+    let manifest = Manifest::read(repo_directory)?;
+
+    let git = |args: &'static [&'static str]| async move {
+        Command::new("git")
+            .arg("-C")
+            .arg(repo_directory)
+            .args(args)
+            .stderr(Stdio::null())
+            .output()
+            .await
+            .ok()
+            .filter(|o| o.status.success())
+            .and_then(|o| String::from_utf8(o.stdout).ok())
+            .and_then(|o| o.lines().next().map(|l| l.trim().to_string()))
+            .filter(|o| !o.is_empty())
+    };
+
+    Ok(BuildInfo {
+        name: manifest.name,
+        version: manifest.version,
+        commit: git(&["rev-parse", "HEAD"]).await,
+        tag: git(&["tag", "--points-at", "HEAD"]).await,
+    })
+}
+
+/// Whether `latest` is a newer version than `current`.
+/// `false` when either of them is not a semver version, as there is no telling then
+/// whether updating would not be a downgrade.
+pub fn is_newer_version(latest: &str, current: &str) -> bool {
+    let parse = |v: &str| semver::Version::parse(v.trim_start_matches('v'));
+    match (parse(latest), parse(current)) {
+        (Ok(latest), Ok(current)) => latest > current,
+        _ => false,
+    }
+}
+
 /// Get a executables name via Cargo.toml to be /absolutely/ sure its the corrent name.
-pub async fn get_executable_name(target_triple: &String, compilation_directory: &PathBuf) -> String {
-    let mut file_descriptor = File::open(compilation_directory.join(target_triple).join("Cargo.toml"))
-        .await
-        .unwrap();
+pub async fn get_executable_name(target_triple: &String, compilation_directory: &Path) -> String {
+    let mut file_descriptor =
+        File::open(compilation_directory.join(target_triple).join("Cargo.toml"))
+            .await
+            .unwrap();
     let mut string = String::new();
     file_descriptor.read_to_string(&mut string).await.unwrap();
     let executable_name = string.split('\n').find(|s| s.contains("name")).unwrap();
     let mut executable_name = executable_name
         .split('=')
-        .last()
+        .next_back()
         .unwrap()
-        .replace('\"', "")
-        .replace(' ', "");
+        .replace(['\"', ' '], "");
 
     // Account for .exe extension on windows
     if target_triple.contains("windows") {
@@ -228,31 +712,83 @@ pub async fn get_executable_name(target_triple: &String, compilation_directory:
     executable_name
 }
 
-pub fn cross_not_found() -> bool {
-    Command::new("cross")
+/// Works out the url clients reached this server through from the `Host` header.
+pub fn server_url(headers: &HeaderMap) -> String {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .filter(|h| is_safe_path(h))
+        .unwrap_or("localhost:3000");
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|h| h.to_str().ok())
+        .filter(|s| *s == "https")
+        .unwrap_or("http");
+
+    format!("{scheme}://{host}")
+}
+
+/// Whether `path` only consists of characters which are safe to put inside a quoted shell string.
+pub fn is_safe_path(path: &str) -> bool {
+    !path.is_empty()
+        && path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/\\._-:~ ".contains(c))
+}
+
+/// Whether `path` can go inside a double quoted string of a sh script. Backslashes would escape
+/// what follows, and a leading `~` is not expanded inside quotes.
+pub fn is_safe_sh_path(path: &str) -> bool {
+    is_safe_path(path) && !path.contains('\\') && !path.starts_with('~')
+}
+
+/// Whether the executable of the `backend` can not be found.
+pub fn backend_not_found(backend: Backend) -> bool {
+    Command::new(backend.command())
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()
         .is_err()
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Config {
     debug: bool,
     binary_name: Option<String>,
+    package_files: Vec<String>,
+    backend: Backend,
+    build_timeout: Option<Duration>,
 }
 
 impl Config {
-    pub fn new(debug: bool, binary_name: Option<String>) -> Self {
+    pub fn new(
+        debug: bool,
+        binary_name: Option<String>,
+        package_files: Vec<String>,
+        backend: Backend,
+        build_timeout: Option<Duration>,
+    ) -> Self {
         Config {
             debug,
             binary_name,
+            package_files,
+            backend,
+            build_timeout,
         }
     }
-}
 
-impl Default for Config {
-    fn default() -> Self {
-        Self { debug: false, binary_name: None }
+    /// The extra files (relative to the repo root) to put in packaged archives.
+    pub fn package_files(&self) -> &[String] {
+        &self.package_files
+    }
+
+    /// What targets get built with.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// How long a build may take before being aborted, if there is a limit.
+    pub fn build_timeout(&self) -> Option<Duration> {
+        self.build_timeout
     }
 }
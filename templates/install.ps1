@@ -0,0 +1,52 @@
+# Installer script served by gload.
+#
+# Usage: irm {{server}}/install.ps1 | iex
+#
+# Detects the architecture of this machine, asks gload for the matching
+# executable, verifies its checksum and installs it to $env:GLOAD_PREFIX
+# (defaults to {{prefix}}). Set $env:GLOAD_TOKEN when the server needs a token.
+$ErrorActionPreference = "Stop"
+
+$Server = "{{server}}"
+$Prefix = if ($env:GLOAD_PREFIX) { $env:GLOAD_PREFIX } else { "{{prefix}}" }
+$Headers = @{}
+if ($env:GLOAD_TOKEN) { $Headers.Authorization = "Bearer $env:GLOAD_TOKEN" }
+
+$Arch = switch ($env:PROCESSOR_ARCHITECTURE) {
+    "AMD64" { "Win64; x64" }
+    # There are no gnu toolchains for arm windows, x64 executables run fine through emulation though.
+    "ARM64" { "Win64; x64" }
+    "x86" { "Win32; x86" }
+    default { throw "Unsupported architecture: $env:PROCESSOR_ARCHITECTURE" }
+}
+
+Write-Host "Detected Windows on $env:PROCESSOR_ARCHITECTURE, asking $Server for a matching executable."
+$Body = @{ os = "Windows"; os_version = "-"; user_agent = "gload-installer (Windows NT; $Arch)" } | ConvertTo-Json
+$Target = (Invoke-RestMethod -Method Post -Headers $Headers -Uri "$Server/get_target" -ContentType "application/json" -Body $Body).target_triple
+
+# Asking for the checksum first also tells us the name of the executable.
+# This might take a while if the executable has to be compiled.
+Write-Host "Compiling for $Target, this might take a while..."
+$Checksum = (Invoke-WebRequest -UseBasicParsing -Headers $Headers -Uri "$Server/get_checksum/$Target").Content
+if ($Checksum -is [byte[]]) { $Checksum = [System.Text.Encoding]::UTF8.GetString($Checksum) }
+$Expected, $Name = $Checksum.Trim() -split '\s+'
+
+$Temporary = New-TemporaryFile
+try {
+    Invoke-WebRequest -UseBasicParsing -Headers $Headers -Uri "$Server/get_binary/$Target" -OutFile $Temporary
+
+    $Actual = (Get-FileHash -Algorithm SHA256 -Path $Temporary).Hash.ToLower()
+    if ($Actual -ne $Expected) {
+        throw "Checksum mismatch for $Name, expected $Expected but got $Actual"
+    }
+
+    New-Item -ItemType Directory -Force -Path $Prefix | Out-Null
+    Move-Item -Force -Path $Temporary -Destination (Join-Path $Prefix $Name)
+} finally {
+    if (Test-Path $Temporary) { Remove-Item -Force $Temporary }
+}
+
+Write-Host "Installed $Name to $(Join-Path $Prefix $Name)"
+if (-not ($env:PATH -split ';' -contains $Prefix)) {
+    Write-Host "Note: $Prefix is not in your PATH."
+}
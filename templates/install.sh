@@ -0,0 +1,93 @@
+#!/bin/sh
+# Installer script served by gload.
+#
+# Usage: curl -fsSL {{server}}/install.sh | sh
+#
+# Detects the os and architecture of this machine, asks gload for the matching
+# executable, verifies its checksum and installs it to $GLOAD_PREFIX
+# (defaults to {{prefix}}). Set $GLOAD_TOKEN when the server needs a token.
+set -eu
+
+server="{{server}}"
+prefix="${GLOAD_PREFIX:-{{prefix}}}"
+
+fail() {
+    echo "error: $1" >&2
+    exit 1
+}
+
+# Fetches a url to stdout, a second argument is POSTed as JSON. Sends $GLOAD_TOKEN
+# along when it is set, for servers which do not let anyone download.
+fetch() {
+    url="$1"
+    data="${2-}"
+    set --
+    if command -v curl >/dev/null 2>&1; then
+        [ -z "${GLOAD_TOKEN-}" ] || set -- -H "Authorization: Bearer $GLOAD_TOKEN"
+        [ -z "$data" ] || set -- "$@" -H "Content-Type: application/json" -d "$data"
+        curl -fsSL "$@" "$url"
+    elif command -v wget >/dev/null 2>&1; then
+        [ -z "${GLOAD_TOKEN-}" ] || set -- --header "Authorization: Bearer $GLOAD_TOKEN"
+        [ -z "$data" ] || set -- "$@" --header "Content-Type: application/json" --post-data "$data"
+        wget -qO- "$@" "$url"
+    else
+        fail "neither curl nor wget could be found"
+    fi
+}
+
+sha256() {
+    if command -v sha256sum >/dev/null 2>&1; then
+        sha256sum "$1" | cut -d ' ' -f 1
+    elif command -v shasum >/dev/null 2>&1; then
+        shasum -a 256 "$1" | cut -d ' ' -f 1
+    else
+        fail "neither sha256sum nor shasum could be found"
+    fi
+}
+
+set -- $(uname -sm)
+case "$1" in
+    Linux) os="Linux" ;;
+    Darwin) os="Mac OS X" ;;
+    *) fail "unsupported operating system: $1" ;;
+esac
+# Only architectures there are targets for, anything else would get a executable
+# which does not run.
+case "$2" in
+    x86_64 | amd64) arch="x86_64" ;;
+    aarch64 | arm64) arch="aarch64" ;;
+    i386 | i486 | i586 | i686) arch="i686" ;;
+    *) fail "unsupported architecture: $2" ;;
+esac
+[ "$os $arch" != "Mac OS X i686" ] || fail "unsupported architecture: $2 on macOS"
+
+echo "Detected $os on $arch, asking $server for a matching executable."
+response=$(fetch "$server/get_target" "{\"os\": \"$os\", \"os_version\": \"-\", \"user_agent\": \"gload-installer ($os; $arch)\"}") \
+    || fail "could not determine the target for $os on $arch"
+target=$(echo "$response" | sed -n 's/.*"target_triple" *: *"\([^"]*\)".*/\1/p')
+[ -n "$target" ] || fail "could not determine the target for $os on $arch"
+
+# Asking for the checksum first also tells us the name of the executable.
+# This might take a while if the executable has to be compiled.
+echo "Compiling for $target, this might take a while..."
+checksum=$(fetch "$server/get_checksum/$target") || fail "could not get the checksum for $target"
+expected=$(echo "$checksum" | cut -d ' ' -f 1)
+name=$(echo "$checksum" | cut -d ' ' -f 3)
+
+temporary=$(mktemp)
+trap 'rm -f "$temporary"' EXIT
+fetch "$server/get_binary/$target" > "$temporary" || fail "could not download $name"
+
+actual=$(sha256 "$temporary")
+[ "$actual" = "$expected" ] || fail "checksum mismatch for $name, expected $expected but got $actual"
+
+mkdir -p "$prefix"
+mv "$temporary" "$prefix/$name"
+chmod 755 "$prefix/$name"
+trap - EXIT
+
+echo "Installed $name to $prefix/$name"
+case ":$PATH:" in
+    *":$prefix:"*) ;;
+    *) echo "Note: $prefix is not in your PATH." ;;
+esac